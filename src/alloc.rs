@@ -1,11 +1,50 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
 use core::ffi::c_void;
 use defmt::*;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Bytes in the heap shared by Lua and Rust.
+pub const HEAP_SIZE: usize = 65536;
 
 #[global_allocator]
-static ALLOCATOR: emballoc::Allocator<16384> = emballoc::Allocator::new();
+static ALLOCATOR: Counted = Counted(emballoc::Allocator::new());
 extern crate alloc;
 
+// bytes allocated now, and at most since boot
+static USAGE: Mutex<CriticalSectionRawMutex, Cell<(usize, usize)>> = Mutex::new(Cell::new((0, 0)));
+
+/// The heap allocator, keeping count of the bytes it has handed out.
+struct Counted(emballoc::Allocator<HEAP_SIZE>);
+
+unsafe impl GlobalAlloc for Counted {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.0.alloc(layout) };
+        if !ptr.is_null() {
+            USAGE.lock(|usage| {
+                let (used, peak) = usage.get();
+                let used = used + layout.size();
+                usage.set((used, peak.max(used)));
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.0.dealloc(ptr, layout) };
+        USAGE.lock(|usage| {
+            let (used, peak) = usage.get();
+            usage.set((used - layout.size(), peak));
+        });
+    }
+}
+
+/// Bytes of the heap in use now, and at most since boot.
+pub fn heap_usage() -> (usize, usize) {
+    USAGE.lock(|usage| usage.get())
+}
+
 // This will be called instead of malloc
 #[unsafe(no_mangle)]
 pub extern "C" fn malloc(size: usize) -> *mut c_void {
@@ -1,8 +1,17 @@
 //! Logical device driver for the system console.
-
+//!
+//! The UART belongs to core 0. With the `core1` feature, output written on
+//! core 1 goes through a pipe that [`relay_output`] drains on core 0.
+
+#[cfg(feature = "core1")]
+use embassy_futures::block_on;
+#[cfg(feature = "core1")]
+use embassy_rp::multicore::{CoreId, current_core};
 use embassy_rp::uart::{self, Async, UartRx, UartTx};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex;
+#[cfg(feature = "core1")]
+use embassy_sync::pipe::Pipe;
 use static_cell::StaticCell;
 
 type AsyncMutex<T> = mutex::Mutex<CriticalSectionRawMutex, T>;
@@ -54,6 +63,10 @@ impl Console {
 static CONSOLE_CELL: StaticCell<Console> = StaticCell::new();
 static mut CONSOLE_PTR: *const Console = core::ptr::null();
 
+// output from core 1 on its way to the UART
+#[cfg(feature = "core1")]
+static OUTPUT: Pipe<CriticalSectionRawMutex, 256> = Pipe::new();
+
 // generic helper: take a raw mutable pointer to the inner T while holding the async lock
 async fn take_inner_ptr<T>(mutex_handle: &'static AsyncMutex<T>) -> *mut T {
     let mut guard = mutex_handle.lock().await;
@@ -105,11 +118,30 @@ pub async fn console_write(out_string: &str) {
 
 // SAFETY: Only safe if all sync and async console users are running under the same
 //         Embassy executor.
+//         Writes from core 1 go through relay_output instead.
 pub fn console_write_blocking(out_string: &str) -> Result<(), uart::Error> {
+    #[cfg(feature = "core1")]
+    if current_core() == CoreId::Core1 {
+        block_on(OUTPUT.write_all(out_string.as_bytes()));
+        return Ok(());
+    }
     let tx = unsafe { console().tx_inner_mut() };
     tx.blocking_write(out_string.as_bytes())
 }
 
+/// Write what core 1 sends to the console. Must run on core 0, for as long
+/// as core 1 writes.
+#[cfg(feature = "core1")]
+pub async fn relay_output() -> ! {
+    let mut chunk = [0; 64];
+    loop {
+        let n = OUTPUT.read(&mut chunk).await;
+        let uart_mutex = console().tx_mutex();
+        let mut guard = uart_mutex.lock().await;
+        guard.write(&chunk[..n]).await.unwrap();
+    }
+}
+
 pub fn console_read_blocking() -> Result<u8, uart::Error> {
     Ok(b'a')
 }
@@ -0,0 +1,213 @@
+//! Crash reports that survive a reset.
+//!
+//! The panic handler logs the panic over defmt, writes its message, location
+//! and the return addresses it finds on the stack to RAM the runtime leaves
+//! uninitialised, and resets the chip. On the next boot [`init`] moves the
+//! report out of there, for the console and `sys.last_crash()`.
+
+use core::cell::RefCell;
+use core::fmt::{Display, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+extern crate alloc;
+use alloc::string::String;
+
+/// Marks a report as written by the panic handler rather than left over
+/// from power-on.
+const MAGIC: u32 = 0x4352_5348;
+const MESSAGE_LEN: usize = 128;
+const FILE_LEN: usize = 64;
+/// Return addresses kept from the stack.
+const STACK_DEPTH: usize = 8;
+/// Stack words searched for return addresses.
+const STACK_SCAN: usize = 256;
+
+#[derive(Clone, Copy)]
+pub struct Report {
+    magic: u32,
+    line: u32,
+    message_len: u32,
+    file_len: u32,
+    message: [u8; MESSAGE_LEN],
+    file: [u8; FILE_LEN],
+    stack_len: u32,
+    stack: [u32; STACK_DEPTH],
+}
+
+impl Report {
+    /// A report of a panic with `message` at `file`:`line`, each cut to fit.
+    pub fn new(message: impl Display, file: &str, line: u32) -> Self {
+        let mut report = Report {
+            magic: MAGIC,
+            line,
+            message_len: 0,
+            file_len: 0,
+            message: [0; MESSAGE_LEN],
+            file: [0; FILE_LEN],
+            stack_len: 0,
+            stack: [0; STACK_DEPTH],
+        };
+        let mut truncated = Truncate {
+            buffer: &mut report.message,
+            len: 0,
+        };
+        write!(truncated, "{message}").ok();
+        report.message_len = truncated.len;
+        let mut truncated = Truncate {
+            buffer: &mut report.file,
+            len: 0,
+        };
+        truncated.write_str(file).ok();
+        report.file_len = truncated.len;
+        report
+    }
+
+    pub fn message(&self) -> &str {
+        text(&self.message[..self.message_len as usize])
+    }
+
+    pub fn file(&self) -> &str {
+        text(&self.file[..self.file_len as usize])
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// Likely return addresses, innermost first: code addresses found on
+    /// the stack when it panicked.
+    pub fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_len as usize]
+    }
+
+    /// Whether the report was written by the panic handler, rather than
+    /// being whatever RAM held at power-on.
+    pub fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.message_len as usize <= MESSAGE_LEN
+            && self.file_len as usize <= FILE_LEN
+            && self.stack_len as usize <= STACK_DEPTH
+    }
+}
+
+/// The part of `bytes` that is UTF-8; a message cut short can end mid
+/// character.
+fn text(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+// written by the panic handler, read back by `init` after the reset
+#[unsafe(link_section = ".uninit.CRASH")]
+static mut SAVED: MaybeUninit<Report> = MaybeUninit::uninit();
+
+static LAST: Mutex<CriticalSectionRawMutex, RefCell<Option<Report>>> =
+    Mutex::new(RefCell::new(None));
+
+unsafe extern "C" {
+    // from cortex-m-rt's linker script
+    static __stext: u8;
+    static __etext: u8;
+    static _stack_start: u8;
+}
+
+/// Take the report the panic handler left before the last reset, if any.
+/// Call once at boot.
+pub fn init() {
+    let saved = &raw mut SAVED;
+    // whatever the RAM holds is a valid `Report` bit pattern
+    let report = unsafe { (*saved).assume_init_read() };
+    if report.is_valid() {
+        unsafe { (*saved).as_mut_ptr().cast::<u32>().write_volatile(0) };
+        LAST.lock(|last| last.replace(Some(report)));
+    }
+}
+
+/// The report of the panic that caused the last reset.
+pub fn last() -> Option<Report> {
+    LAST.lock(|last| *last.borrow())
+}
+
+/// [`last`] as text for the console.
+pub fn last_text() -> Option<String> {
+    let report = last()?;
+    let mut text = String::new();
+    write!(
+        text,
+        "Last crash: panicked at {}:{}: {}\r\n  stack:",
+        report.file(),
+        report.line(),
+        report.message()
+    )
+    .ok();
+    for addr in report.stack() {
+        write!(text, " {addr:#010x}").ok();
+    }
+    text.push_str("\r\n");
+    Some(text)
+}
+
+/// Writes into a fixed buffer, dropping what does not fit.
+struct Truncate<'a> {
+    buffer: &'a mut [u8],
+    len: u32,
+}
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let start = self.len as usize;
+        let n = s.len().min(self.buffer.len() - start);
+        self.buffer[start..start + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n as u32;
+        Ok(())
+    }
+}
+
+/// Collect the words between `sp` and the top of RAM that point just past
+/// a call in the code: return addresses, with the Thumb bit set.
+fn scan_stack(sp: usize, stack: &mut [u32; STACK_DEPTH]) -> u32 {
+    let code = (&raw const __stext as u32)..(&raw const __etext as u32);
+    let top = &raw const _stack_start as usize;
+    let mut found = 0;
+    let mut addr = sp & !3;
+    for _ in 0..STACK_SCAN {
+        if addr >= top || found == STACK_DEPTH {
+            break;
+        }
+        let word = unsafe { (addr as *const u32).read_volatile() };
+        if word & 1 == 1 && code.contains(&(word & !1)) {
+            stack[found] = word & !1;
+            found += 1;
+        }
+        addr += 4;
+    }
+    found as u32
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    let (file, line) = info
+        .location()
+        .map_or(("", 0), |location| (location.file(), location.line()));
+    let mut report = Report::new(info.message(), file, line);
+    report.stack_len = scan_stack(cortex_m::register::msp::read() as usize, &mut report.stack);
+    unsafe { (&raw mut SAVED).write(MaybeUninit::new(report)) };
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// `defmt::panic!`, `unwrap!` and the like, as the embassy crates use them,
+/// log their message over defmt and end up here without it or a location.
+/// This crate panics with `core::panic!` instead.
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    core::panic!("defmt panic, see the defmt log")
+}
@@ -1,10 +1,18 @@
 use crate::console_ldd::console_write_blocking;
-use core::ffi::{c_char, c_int, c_long, c_void};
+use crate::crash::Report;
+use crate::lua_limit::{CallError, Limits, pcall_limited};
+use crate::lua_state::{Config, LuaState, SANDBOX_GLOBALS, Sandbox};
+use core::ffi::{CStr, c_char, c_int, c_long, c_ulong, c_void};
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Instant};
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
 use defmt::*;
 
 unsafe extern "C" {
-    pub unsafe fn luaL_newstate() -> *mut c_void;
-    pub unsafe fn luaL_openlibs(state: *mut c_void);
     pub unsafe fn lua_tolstring(
         state: *mut c_void,
         index: c_int,
@@ -18,6 +26,14 @@ unsafe extern "C" {
         n: c_int,
     );
 
+    pub unsafe fn lua_callk(
+        state: *mut c_void,
+        nargs: c_int,
+        nresults: c_int,
+        ctx: isize,
+        k: *const c_void,
+    );
+
     pub unsafe fn lua_pcallk(
         state: *mut c_void,
         nargs: c_int,
@@ -30,35 +46,192 @@ unsafe extern "C" {
     pub unsafe fn lua_getglobal(state: *mut c_void, k: *const c_char) -> c_int;
 
     pub unsafe fn lua_settop(state: *mut c_void, idx: c_int);
-    //pub unsafe fn luaL_loadbufferx(
-    //    state: *mut c_void,
-    //    buff: *const c_char,
-    //    sz: usize,
-    //    name: *const c_char,
-    //    mode: *const c_char,
-    //) -> c_int;
+    pub unsafe fn luaL_loadbufferx(
+        state: *mut c_void,
+        buff: *const c_char,
+        sz: usize,
+        name: *const c_char,
+        mode: *const c_char,
+    ) -> c_int;
     pub unsafe fn luaL_loadstring(state: *mut c_void, s: *const c_char) -> c_int;
     pub unsafe fn lua_close(state: *mut c_void);
-    pub unsafe fn lua_tointegerx(state: *mut c_void, idx: c_int, isnum: *mut c_int) -> c_int;
+    pub unsafe fn lua_tointegerx(state: *mut c_void, idx: c_int, isnum: *mut c_int) -> c_long;
+    pub unsafe fn lua_tonumberx(state: *mut c_void, idx: c_int, isnum: *mut c_int) -> f64;
+    pub unsafe fn lua_toboolean(state: *mut c_void, idx: c_int) -> c_int;
+    pub unsafe fn lua_type(state: *mut c_void, idx: c_int) -> c_int;
+
+    pub unsafe fn lua_pushnil(state: *mut c_void);
+    pub unsafe fn lua_pushboolean(state: *mut c_void, b: c_int);
+    pub unsafe fn lua_pushinteger(state: *mut c_void, n: c_long);
+    pub unsafe fn lua_pushnumber(state: *mut c_void, n: f64);
+    pub unsafe fn lua_pushlstring(
+        state: *mut c_void,
+        s: *const c_char,
+        len: usize,
+    ) -> *const c_char;
+    pub unsafe fn lua_pushvalue(state: *mut c_void, idx: c_int);
+
+    pub unsafe fn lua_gettop(state: *mut c_void) -> c_int;
+    pub unsafe fn luaL_checkstack(state: *mut c_void, sz: c_int, msg: *const c_char);
+    pub unsafe fn lua_absindex(state: *mut c_void, idx: c_int) -> c_int;
+    pub unsafe fn lua_setglobal(state: *mut c_void, name: *const c_char);
+
+    pub unsafe fn lua_createtable(state: *mut c_void, narr: c_int, nrec: c_int);
+    pub unsafe fn lua_rawget(state: *mut c_void, idx: c_int) -> c_int;
+    pub unsafe fn lua_rawset(state: *mut c_void, idx: c_int);
+    pub unsafe fn lua_rawlen(state: *mut c_void, idx: c_int) -> c_ulong;
+    pub unsafe fn lua_next(state: *mut c_void, idx: c_int) -> c_int;
+    pub unsafe fn lua_setfield(state: *mut c_void, idx: c_int, k: *const c_char);
+
+    pub unsafe fn lua_newuserdatauv(state: *mut c_void, sz: usize, nuvalue: c_int) -> *mut c_void;
+    pub unsafe fn lua_setmetatable(state: *mut c_void, idx: c_int) -> c_int;
+    pub unsafe fn luaL_newmetatable(state: *mut c_void, tname: *const c_char) -> c_int;
+    pub unsafe fn luaL_checkudata(
+        state: *mut c_void,
+        ud: c_int,
+        tname: *const c_char,
+    ) -> *mut c_void;
+    pub unsafe fn luaL_checkinteger(state: *mut c_void, arg: c_int) -> c_long;
+    pub unsafe fn luaL_checknumber(state: *mut c_void, arg: c_int) -> f64;
+    pub unsafe fn luaL_checklstring(
+        state: *mut c_void,
+        arg: c_int,
+        len: *mut usize,
+    ) -> *const c_char;
+    pub unsafe fn luaL_optinteger(state: *mut c_void, arg: c_int, def: c_long) -> c_long;
+    pub unsafe fn lua_rawgeti(state: *mut c_void, idx: c_int, n: c_long) -> c_int;
+    pub unsafe fn lua_rotate(state: *mut c_void, idx: c_int, n: c_int);
+    pub unsafe fn luaL_ref(state: *mut c_void, t: c_int) -> c_int;
+    pub unsafe fn luaL_unref(state: *mut c_void, t: c_int, reference: c_int);
+    pub unsafe fn lua_typename(state: *mut c_void, tp: c_int) -> *const c_char;
+    pub unsafe fn luaL_callmeta(state: *mut c_void, obj: c_int, e: *const c_char) -> c_int;
+    pub unsafe fn luaL_traceback(
+        state: *mut c_void,
+        state1: *mut c_void,
+        msg: *const c_char,
+        level: c_int,
+    );
+    pub unsafe fn luaL_checkoption(
+        state: *mut c_void,
+        arg: c_int,
+        def: *const c_char,
+        lst: *const *const c_char,
+    ) -> c_int;
+    pub unsafe fn luaL_requiref(
+        state: *mut c_void,
+        modname: *const c_char,
+        openf: LuaCFunction,
+        glb: c_int,
+    );
+    pub unsafe fn lua_rawseti(state: *mut c_void, idx: c_int, n: c_long);
+    pub unsafe fn luaL_getsubtable(state: *mut c_void, idx: c_int, fname: *const c_char) -> c_int;
+    pub unsafe fn luaL_checktype(state: *mut c_void, arg: c_int, t: c_int);
+    pub unsafe fn lua_sethook(state: *mut c_void, func: Option<LuaHook>, mask: c_int, count: c_int);
+    pub unsafe fn lua_pushfstring(state: *mut c_void, fmt: *const c_char, ...) -> *const c_char;
+    pub unsafe fn lua_getfield(state: *mut c_void, idx: c_int, k: *const c_char) -> c_int;
+    pub unsafe fn lua_newthread(state: *mut c_void) -> *mut c_void;
+    pub unsafe fn lua_resume(
+        state: *mut c_void,
+        from: *mut c_void,
+        narg: c_int,
+        nres: *mut c_int,
+    ) -> c_int;
+    pub unsafe fn lua_yieldk(
+        state: *mut c_void,
+        nresults: c_int,
+        ctx: isize,
+        k: Option<LuaKFunction>,
+    ) -> c_int;
+    pub unsafe fn lua_isyieldable(state: *mut c_void) -> c_int;
+    pub unsafe fn lua_xmove(from: *mut c_void, to: *mut c_void, n: c_int);
+    pub unsafe fn lua_rawequal(state: *mut c_void, idx1: c_int, idx2: c_int) -> c_int;
+    pub unsafe fn luaL_checkany(state: *mut c_void, arg: c_int);
+    pub unsafe fn lua_newstate(f: LuaAlloc, ud: *mut c_void) -> *mut c_void;
+    pub unsafe fn lua_atpanic(state: *mut c_void, panicf: LuaCFunction) -> Option<LuaCFunction>;
+    pub unsafe fn lua_pushlightuserdata(state: *mut c_void, p: *mut c_void);
+    pub unsafe fn lua_touserdata(state: *mut c_void, idx: c_int) -> *mut c_void;
+    pub unsafe fn lua_isinteger(state: *mut c_void, idx: c_int) -> c_int;
+    pub unsafe fn lua_gethook(state: *mut c_void) -> Option<LuaHook>;
+    pub unsafe fn lua_gethookmask(state: *mut c_void) -> c_int;
+    pub unsafe fn lua_gethookcount(state: *mut c_void) -> c_int;
+    pub unsafe fn lua_getallocf(state: *mut c_void, ud: *mut *mut c_void) -> LuaAlloc;
+    pub unsafe fn lua_setupvalue(state: *mut c_void, funcindex: c_int, n: c_int) -> *const c_char;
 }
 
-const LUA_OK: i32 = 0;
-//const LUA_YIELD: i32 = 1;
-const LUA_ERRRUN: i32 = 2;
+// The standard libraries linit.c opens.
+unsafe extern "C-unwind" {
+    unsafe fn luaopen_base(state: *mut c_void) -> c_int;
+    unsafe fn luaopen_table(state: *mut c_void) -> c_int;
+    unsafe fn luaopen_io(state: *mut c_void) -> c_int;
+    unsafe fn luaopen_string(state: *mut c_void) -> c_int;
+    unsafe fn luaopen_math(state: *mut c_void) -> c_int;
+}
+
+/// Signature of a native function callable from Lua.
+pub type LuaCFunction = unsafe extern "C-unwind" fn(state: *mut c_void) -> c_int;
+
+/// Signature of a memory allocator for `lua_newstate`.
+pub type LuaAlloc = unsafe extern "C" fn(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void;
+
+/// Signature of a continuation, called when a native function that yielded
+/// is resumed.
+pub type LuaKFunction =
+    unsafe extern "C-unwind" fn(state: *mut c_void, status: c_int, ctx: isize) -> c_int;
+
+/// Signature of a debug hook; `ar` is an opaque `lua_Debug` pointer.
+pub type LuaHook = unsafe extern "C-unwind" fn(state: *mut c_void, ar: *mut c_void);
+
+pub const LUA_OK: i32 = 0;
+pub const LUA_YIELD: i32 = 1;
+pub const LUA_ERRRUN: i32 = 2;
 //const LUA_ERRSYNTAX: i32 = 3;
 //const LUA_ERRMEM: i32 = 4;
 //const LUA_ERRERR: i32 = 5;
 
 //const LUA_MULTRET: i32 = -1;
 
+// -LUAI_MAXSTACK - 1000, with LUAI_MAXSTACK = 1000000 for 32-bit int
+pub const LUA_REGISTRYINDEX: i32 = -1_001_000;
+/// Registry index of the globals table.
+pub const LUA_RIDX_GLOBALS: c_long = 2;
+
+/// Pseudo-index of upvalue `i` of the running C function.
+pub const fn lua_upvalueindex(i: c_int) -> c_int {
+    LUA_REGISTRYINDEX - i
+}
+
+pub const LUA_TNIL: i32 = 0;
+pub const LUA_TBOOLEAN: i32 = 1;
+//const LUA_TLIGHTUSERDATA: i32 = 2;
+pub const LUA_TNUMBER: i32 = 3;
+pub const LUA_TSTRING: i32 = 4;
+pub const LUA_TTABLE: i32 = 5;
+pub const LUA_TFUNCTION: i32 = 6;
+pub const LUA_TUSERDATA: i32 = 7;
+//const LUA_TTHREAD: i32 = 8;
+
+//const LUA_MASKCALL: i32 = 1 << 0;
+//const LUA_MASKRET: i32 = 1 << 1;
+//const LUA_MASKLINE: i32 = 1 << 2;
+pub const LUA_MASKCOUNT: i32 = 1 << 3;
+
 macro_rules! my_assert {
     ($condition:expr) => {
         if !$condition {
-            crate::panic!("Assertion failed: {}", stringify!($condition));
+            core::panic!("Assertion failed: {}", stringify!($condition));
         }
     };
 }
 
+pub unsafe fn lua_call(state: *mut c_void, nargs: c_int, nresults: c_int) {
+    unsafe { lua_callk(state, nargs, nresults, 0, core::ptr::null()) }
+}
+
 pub unsafe fn lua_pcall(
     state: *mut c_void,
     nargs: c_int,
@@ -68,6 +241,93 @@ pub unsafe fn lua_pcall(
     unsafe { lua_pcallk(state, nargs, nresults, errfunc, 0, core::ptr::null()) }
 }
 
+/// Message handler for protected calls: turns the error object into a string
+/// and appends a traceback of the stack where the error happened.
+unsafe extern "C-unwind" fn msghandler(state: *mut c_void) -> c_int {
+    unsafe {
+        let mut msg = lua_tolstring(state, 1, core::ptr::null_mut());
+        if msg.is_null() {
+            // error object is not a string; does it have a __tostring?
+            if luaL_callmeta(state, 1, c"__tostring".as_ptr()) != 0
+                && lua_type(state, -1) == LUA_TSTRING
+            {
+                return 1;
+            }
+            msg = lua_pushfstring(
+                state,
+                c"(error object is a %s value)".as_ptr(),
+                lua_typename(state, lua_type(state, 1)),
+            );
+        }
+        luaL_traceback(state, state, msg, 1);
+    }
+    1
+}
+
+/// `lua_pcall` with [`msghandler`] installed, so a failing call leaves an
+/// error message followed by a stack traceback.
+pub unsafe fn pcall_traceback(state: *mut c_void, nargs: c_int, nresults: c_int) -> c_int {
+    unsafe {
+        check_stack(state, 1);
+        let base = lua_gettop(state) - nargs; // function index
+        lua_pushcclosure(state, msghandler, 0);
+        lua_rotate(state, base, 1); // lua_insert(state, base)
+        let status = lua_pcall(state, nargs, nresults, base);
+        lua_rotate(state, base, -1); // lua_remove(state, base)
+        lua_pop(state, 1);
+        status
+    }
+}
+
+pub unsafe fn lua_pop(state: *mut c_void, n: c_int) {
+    unsafe { lua_settop(state, -(n) - 1) }
+}
+
+/// Debug check that a scope leaves the Lua stack at the height it expects.
+///
+/// Records `lua_gettop` on creation; [`check`](Self::check) asserts, in debug
+/// builds, that the stack has grown by exactly `results` slots. The check is
+/// not done on drop: a Lua error longjmps out of the scope, and must not skip
+/// a destructor on the way.
+#[must_use]
+pub struct StackGuard {
+    state: *mut c_void,
+    expected: c_int,
+}
+
+impl StackGuard {
+    /// The scope must leave the stack as it found it.
+    pub unsafe fn new(state: *mut c_void) -> Self {
+        unsafe { Self::with_results(state, 0) }
+    }
+
+    /// The scope must leave `results` more values on the stack than it found.
+    pub unsafe fn with_results(state: *mut c_void, results: c_int) -> Self {
+        unsafe {
+            Self {
+                state,
+                expected: lua_gettop(state) + results,
+            }
+        }
+    }
+
+    /// Assert that the scope left the stack as expected.
+    pub fn check(self) {
+        let top = unsafe { lua_gettop(self.state) };
+        defmt::debug_assert!(
+            top == self.expected,
+            "Lua stack unbalanced: top is {}, expected {}",
+            top,
+            self.expected
+        );
+    }
+}
+
+/// Make room for `n` more values, raising a Lua error if the stack cannot grow.
+pub unsafe fn check_stack(state: *mut c_void, n: c_int) {
+    unsafe { luaL_checkstack(state, n, core::ptr::null()) }
+}
+
 // print a string
 #[unsafe(no_mangle)]
 pub extern "C" fn lua_writestring(s: *const c_char, l: usize) {
@@ -101,12 +361,23 @@ pub extern "C" fn lua_writestringerror(s: *const c_char, l: usize) {
     }
 }
 
-unsafe fn to_string<'a>(state: *mut c_void, index: c_int) -> &'a str {
+/// The string at `index`, or `None` if its bytes are not UTF-8.
+unsafe fn to_string<'a>(state: *mut c_void, index: c_int) -> Option<&'a str> {
     let mut len: c_long = 0;
     unsafe {
         let ptr = lua_tolstring(state, index, &mut len);
         let bytes = core::slice::from_raw_parts(ptr, len as usize);
-        core::str::from_utf8(bytes).unwrap()
+        core::str::from_utf8(bytes).ok()
+    }
+}
+
+/// Argument `arg` as the bytes of a Lua string. The slice borrows the string,
+/// so it stays valid while the argument is on the stack.
+pub unsafe fn check_bytes<'a>(state: *mut c_void, arg: c_int) -> &'a [u8] {
+    let mut len = 0;
+    unsafe {
+        let ptr = luaL_checklstring(state, arg, &mut len);
+        core::slice::from_raw_parts(ptr, len)
     }
 }
 
@@ -118,14 +389,529 @@ fn to_cstring(rstring: &str, buffer: &mut [u8; 256]) -> *const c_char {
     buffer.as_ptr()
 }
 
-unsafe fn report(state: *mut c_void, status: i32) -> i32 {
+/// A value that can be pushed onto the Lua stack.
+pub trait ToLua {
+    unsafe fn push(&self, state: *mut c_void);
+}
+
+/// A value that can be read back from a slot on the Lua stack.
+///
+/// Conversions are strict: a number is never turned into a string (that would
+/// confuse `lua_next`), and strings are never coerced into numbers.
+pub trait FromLua: Sized {
+    unsafe fn from_lua(state: *mut c_void, idx: c_int) -> Option<Self>;
+}
+
+impl ToLua for bool {
+    unsafe fn push(&self, state: *mut c_void) {
+        unsafe { lua_pushboolean(state, *self as c_int) }
+    }
+}
+
+impl ToLua for c_long {
+    unsafe fn push(&self, state: *mut c_void) {
+        unsafe { lua_pushinteger(state, *self) }
+    }
+}
+
+impl ToLua for f64 {
+    unsafe fn push(&self, state: *mut c_void) {
+        unsafe { lua_pushnumber(state, *self) }
+    }
+}
+
+impl ToLua for &str {
+    unsafe fn push(&self, state: *mut c_void) {
+        unsafe {
+            lua_pushlstring(state, self.as_ptr() as *const c_char, self.len());
+        }
+    }
+}
+
+impl FromLua for bool {
+    unsafe fn from_lua(state: *mut c_void, idx: c_int) -> Option<Self> {
+        unsafe {
+            match lua_type(state, idx) {
+                LUA_TBOOLEAN => Some(lua_toboolean(state, idx) != 0),
+                _ => None,
+            }
+        }
+    }
+}
+
+impl FromLua for c_long {
+    unsafe fn from_lua(state: *mut c_void, idx: c_int) -> Option<Self> {
+        unsafe {
+            if lua_type(state, idx) != LUA_TNUMBER {
+                return None;
+            }
+            let mut isnum: c_int = 0;
+            let n = lua_tointegerx(state, idx, &mut isnum);
+            (isnum != 0).then_some(n)
+        }
+    }
+}
+
+impl FromLua for f64 {
+    unsafe fn from_lua(state: *mut c_void, idx: c_int) -> Option<Self> {
+        unsafe {
+            if lua_type(state, idx) != LUA_TNUMBER {
+                return None;
+            }
+            Some(lua_tonumberx(state, idx, core::ptr::null_mut()))
+        }
+    }
+}
+
+impl FromLua for String {
+    unsafe fn from_lua(state: *mut c_void, idx: c_int) -> Option<Self> {
+        unsafe {
+            if lua_type(state, idx) != LUA_TSTRING {
+                return None;
+            }
+            to_string(state, idx).map(String::from)
+        }
+    }
+}
+
+/// Handle to a table living in a fixed slot of the Lua stack.
+///
+/// The handle does not own the slot. The table stays valid only while it is
+/// left on the stack; popping it is up to the caller.
+pub struct LuaTable {
+    state: *mut c_void,
+    index: c_int,
+}
+
+impl LuaTable {
+    /// Push a new table with room preallocated for `narr` array entries and
+    /// `nrec` hash entries.
+    pub unsafe fn new(state: *mut c_void, narr: c_int, nrec: c_int) -> Self {
+        unsafe {
+            lua_createtable(state, narr, nrec);
+            Self {
+                state,
+                index: lua_gettop(state),
+            }
+        }
+    }
+
+    /// Wrap the table at `idx`, or return `None` if that slot is not a table.
+    pub unsafe fn from_stack(state: *mut c_void, idx: c_int) -> Option<Self> {
+        unsafe {
+            if lua_type(state, idx) != LUA_TTABLE {
+                return None;
+            }
+            Some(Self {
+                state,
+                index: lua_absindex(state, idx),
+            })
+        }
+    }
+
+    /// Absolute stack index of the table.
+    pub fn index(&self) -> c_int {
+        self.index
+    }
+
+    /// `t[key]` without invoking metamethods. Returns `None` for a missing key
+    /// or a value that does not convert to `V`.
+    pub fn get<K: ToLua, V: FromLua>(&self, key: K) -> Option<V> {
+        unsafe {
+            let guard = StackGuard::new(self.state);
+            check_stack(self.state, 1);
+            key.push(self.state);
+            lua_rawget(self.state, self.index);
+            let value = V::from_lua(self.state, -1);
+            lua_pop(self.state, 1);
+            guard.check();
+            value
+        }
+    }
+
+    /// String field `key` without invoking metamethods, borrowed from the
+    /// table. Returns `None` for a missing key or a value that is not a UTF-8
+    /// string. Unlike a `String` from [`get`](Self::get) it has nothing to
+    /// drop, so a Lua error may be raised while it is in use.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        unsafe {
+            let guard = StackGuard::new(self.state);
+            check_stack(self.state, 1);
+            key.push(self.state);
+            let value = if lua_rawget(self.state, self.index) == LUA_TSTRING {
+                // the table keeps the string alive
+                to_string(self.state, -1)
+            } else {
+                None
+            };
+            lua_pop(self.state, 1);
+            guard.check();
+            value
+        }
+    }
+
+    /// `t[key] = value` without invoking metamethods.
+    pub fn set<K: ToLua, V: ToLua>(&self, key: K, value: V) {
+        unsafe {
+            let guard = StackGuard::new(self.state);
+            check_stack(self.state, 2);
+            key.push(self.state);
+            value.push(self.state);
+            lua_rawset(self.state, self.index);
+            guard.check();
+        }
+    }
+
+    /// Raw length of the table (`#t` without `__len`).
+    pub fn len(&self) -> usize {
+        unsafe { lua_rawlen(self.state, self.index) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs::<Skip, Skip>().next().is_none()
+    }
+
+    /// Iterate over all entries. Entries whose key or value do not convert to
+    /// `K`/`V` are skipped.
+    pub fn pairs<K: FromLua, V: FromLua>(&self) -> LuaTablePairs<'_, K, V> {
+        unsafe {
+            // room for the key plus the value lua_next pushes
+            check_stack(self.state, 2);
+            lua_pushnil(self.state);
+        }
+        LuaTablePairs {
+            table: self,
+            key_on_stack: true,
+            _marker: core::marker::PhantomData,
+        }
+    }
+
+    /// Store a copy of the table reference in the global `name`.
+    pub fn set_global(&self, name: &core::ffi::CStr) {
+        unsafe {
+            let guard = StackGuard::new(self.state);
+            check_stack(self.state, 1);
+            lua_pushvalue(self.state, self.index);
+            lua_setglobal(self.state, name.as_ptr());
+            guard.check();
+        }
+    }
+}
+
+/// Matches any Lua value without converting it.
+pub struct Skip;
+
+impl FromLua for Skip {
+    unsafe fn from_lua(_state: *mut c_void, _idx: c_int) -> Option<Self> {
+        Some(Skip)
+    }
+}
+
+/// Iterator returned by [`LuaTable::pairs`], driving `lua_next`.
+///
+/// While iterating, the current key occupies the top of the stack. Dropping
+/// the iterator early removes it.
+pub struct LuaTablePairs<'a, K, V> {
+    table: &'a LuaTable,
+    key_on_stack: bool,
+    _marker: core::marker::PhantomData<(K, V)>,
+}
+
+impl<K: FromLua, V: FromLua> Iterator for LuaTablePairs<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let state = self.table.state;
+        while self.key_on_stack {
+            unsafe {
+                if lua_next(state, self.table.index) == 0 {
+                    // lua_next popped the last key
+                    self.key_on_stack = false;
+                    break;
+                }
+                let entry = K::from_lua(state, -2).zip(V::from_lua(state, -1));
+                lua_pop(state, 1); // pop the value, keep the key for lua_next
+                if entry.is_some() {
+                    return entry;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<K, V> Drop for LuaTablePairs<'_, K, V> {
+    fn drop(&mut self) {
+        if self.key_on_stack {
+            unsafe { lua_pop(self.table.state, 1) };
+        }
+    }
+}
+
+/// Push a new table holding `funcs`, like `luaL_newlib`.
+pub unsafe fn new_lib(state: *mut c_void, funcs: &[(&CStr, LuaCFunction)]) {
+    unsafe {
+        let guard = StackGuard::with_results(state, 1);
+        check_stack(state, 2);
+        lua_createtable(state, 0, funcs.len() as c_int);
+        for (name, func) in funcs {
+            lua_pushcclosure(state, *func, 0);
+            lua_setfield(state, -2, name.as_ptr());
+        }
+        guard.check();
+    }
+}
+
+/// The standard libraries built into the firmware: those `luaL_openlibs`
+/// opens, and `os` adapted to the board.
+pub const STD_LIBS: &[(&CStr, LuaCFunction)] = &[
+    (c"_G", luaopen_base),
+    (c"table", luaopen_table),
+    (c"io", luaopen_io),
+    (c"string", luaopen_string),
+    (c"math", luaopen_math),
+    (c"os", crate::lua_os::luaopen_os),
+];
+
+/// Libraries for the board's peripherals and the firmware's services,
+/// opened next to the standard ones.
+pub const BOARD_LIBS: &[(&CStr, LuaCFunction)] = &[
+    (c"gpio", crate::lua_gpio::luaopen_gpio),
+    (c"event", crate::lua_event::luaopen_event),
+    (c"pwm", crate::lua_pwm::luaopen_pwm),
+    (c"adc", crate::lua_adc::luaopen_adc),
+    (c"i2c", crate::lua_i2c::luaopen_i2c),
+    (c"spi", crate::lua_spi::luaopen_spi),
+    (c"uart", crate::lua_uart::luaopen_uart),
+    (c"pio", crate::lua_pio::luaopen_pio),
+    (c"neopixel", crate::lua_neopixel::luaopen_neopixel),
+    (c"time", crate::lua_time::luaopen_time),
+    (c"task", crate::lua_task::luaopen_task),
+    (c"msg", crate::lua_msg::luaopen_msg),
+    (c"sys", crate::lua_sys::luaopen_sys),
+];
+
+/// Open `libs` and set each as a global, like `luaL_openlibs` does for the
+/// standard libraries.
+pub unsafe fn open_libs(state: *mut c_void, libs: &[(&CStr, LuaCFunction)]) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        check_stack(state, 1);
+        for (name, open) in libs {
+            luaL_requiref(state, name.as_ptr(), *open, 1);
+            lua_pop(state, 1);
+        }
+        guard.check();
+    }
+}
+
+/// A Rust value that can be handed to Lua as a full userdata object.
+///
+/// `NAME` is the registry name of the type's metatable and must be unique.
+/// `METHODS` are reachable through `__index`, so scripts call them as
+/// `obj:method(...)`; a method gets at its object with [`check_userdata`] on
+/// argument 1. The value is dropped by `__gc` when Lua collects the object.
+pub trait LuaUserData: Sized + 'static {
+    const NAME: &'static CStr;
+    const METHODS: &'static [(&'static CStr, LuaCFunction)];
+}
+
+// Userdata memory is only as aligned as malloc's cookie allows, so the value
+// is placed at the first suitably aligned address inside the block.
+fn userdata_slot<T>(raw: *mut c_void) -> *mut Option<T> {
+    let raw = raw as *mut u8;
+    raw.wrapping_add(raw.align_offset(align_of::<Option<T>>()))
+        .cast()
+}
+
+/// Move `value` into a new userdata on top of the stack, creating the
+/// metatable for `T` on first use. If Lua runs out of memory for the
+/// userdata, `value` is leaked.
+pub unsafe fn push_userdata<T: LuaUserData>(state: *mut c_void, value: T) {
+    // no destructor for the error below to skip
+    let value = ManuallyDrop::new(value);
+    unsafe {
+        let guard = StackGuard::with_results(state, 1);
+        // userdata, metatable, methods table and one method
+        check_stack(state, 4);
+        let size = size_of::<Option<T>>() + align_of::<Option<T>>() - 1;
+        let raw = lua_newuserdatauv(state, size, 0);
+        userdata_slot::<T>(raw).write(Some(ManuallyDrop::into_inner(value)));
+
+        if luaL_newmetatable(state, T::NAME.as_ptr()) != 0 {
+            new_lib(state, T::METHODS);
+            lua_setfield(state, -2, c"__index".as_ptr());
+            lua_pushcclosure(state, userdata_gc::<T>, 0);
+            lua_setfield(state, -2, c"__gc".as_ptr());
+            lua_pushcclosure(state, userdata_tostring::<T>, 0);
+            lua_setfield(state, -2, c"__tostring".as_ptr());
+        }
+        lua_setmetatable(state, -2);
+        guard.check();
+    }
+}
+
+/// Push a userdata holding `len` zeroed `T`s and return them, e.g. as a
+/// buffer for a transfer. Lua owns the memory, so the collector frees it even
+/// when a Lua error jumps out of the caller, where a `Vec` would leak. The
+/// slice stays valid while the userdata is on the stack.
+pub unsafe fn push_buffer<'a, T: Copy + Default>(state: *mut c_void, len: usize) -> &'a mut [T] {
+    unsafe {
+        check_stack(state, 1);
+        let raw =
+            lua_newuserdatauv(state, len * size_of::<T>() + align_of::<T>() - 1, 0) as *mut u8;
+        // aligned like a userdata_slot
+        let data = raw
+            .wrapping_add(raw.align_offset(align_of::<T>()))
+            .cast::<T>();
+        let buffer = core::slice::from_raw_parts_mut(data, len);
+        buffer.fill(T::default());
+        buffer
+    }
+}
+
+/// Downcast argument `arg` to a `T`. Raises a Lua error if the argument is
+/// some other value, or an object whose Rust value has already been dropped.
+pub unsafe fn check_userdata<'a, T: LuaUserData>(state: *mut c_void, arg: c_int) -> &'a mut T {
+    unsafe {
+        let raw = luaL_checkudata(state, arg, T::NAME.as_ptr());
+        match &mut *userdata_slot::<T>(raw) {
+            Some(value) => value,
+            None => {
+                luaL_error(
+                    state,
+                    c"attempt to use a closed %s".as_ptr(),
+                    T::NAME.as_ptr(),
+                );
+                core::unreachable!()
+            }
+        }
+    }
+}
+
+/// Drop the Rust value of userdata argument `arg` now rather than waiting for
+/// `__gc`, e.g. to implement a `close` method. Later method calls on the
+/// object raise an error.
+pub unsafe fn close_userdata<T: LuaUserData>(state: *mut c_void, arg: c_int) {
+    unsafe {
+        let raw = luaL_checkudata(state, arg, T::NAME.as_ptr());
+        drop((*userdata_slot::<T>(raw)).take());
+    }
+}
+
+unsafe extern "C-unwind" fn userdata_gc<T: LuaUserData>(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<T>(state, 1) };
+    0
+}
+
+unsafe extern "C-unwind" fn userdata_tostring<T: LuaUserData>(state: *mut c_void) -> c_int {
+    unsafe {
+        let raw = luaL_checkudata(state, 1, T::NAME.as_ptr());
+        let fmt = match &*userdata_slot::<T>(raw) {
+            Some(_) => c"%s: %p",
+            None => c"%s (closed): %p",
+        };
+        lua_pushfstring(state, fmt.as_ptr(), T::NAME.as_ptr(), raw);
+    }
+    1
+}
+
+/// The state's instance of `T`, kept in the registry under `key` and created
+/// on first use. The registry keeps it alive, but Lua code may reach it too,
+/// so do not hold on to the reference across calls into Lua.
+pub unsafe fn registry_userdata<'a, T: LuaUserData + Default>(
+    state: *mut c_void,
+    key: &CStr,
+) -> &'a mut T {
+    unsafe {
+        let guard = StackGuard::new(state);
+        check_stack(state, 2);
+        if lua_getfield(state, LUA_REGISTRYINDEX, key.as_ptr()) != LUA_TUSERDATA {
+            lua_pop(state, 1);
+            push_userdata(state, T::default());
+            lua_pushvalue(state, -1);
+            lua_setfield(state, LUA_REGISTRYINDEX, key.as_ptr());
+        }
+        let value = check_userdata::<T>(state, -1);
+        lua_pop(state, 1);
+        guard.check();
+        value
+    }
+}
+
+/// A Lua value anchored in the registry so it survives being popped off the
+/// stack, e.g. a callback function kept in a Rust struct between calls.
+///
+/// The reference is released when the `LuaRef` is dropped, which must happen
+/// before the state is closed.
+pub struct LuaRef {
+    state: *mut c_void,
+    reference: c_int,
+}
+
+impl LuaRef {
+    /// Pop the value on top of the stack and anchor it in the registry.
+    pub unsafe fn new(state: *mut c_void) -> Self {
+        unsafe {
+            Self {
+                state,
+                reference: luaL_ref(state, LUA_REGISTRYINDEX),
+            }
+        }
+    }
+
+    /// Anchor the value at `idx`, leaving the stack unchanged.
+    pub unsafe fn from_stack(state: *mut c_void, idx: c_int) -> Self {
+        unsafe {
+            lua_pushvalue(state, idx);
+            Self::new(state)
+        }
+    }
+
+    /// Push the referenced value onto the stack.
+    pub fn push(&self) {
+        unsafe {
+            check_stack(self.state, 1);
+            lua_rawgeti(self.state, LUA_REGISTRYINDEX, self.reference as c_long);
+        }
+    }
+
+    /// Call the referenced value in protected mode with the `nargs` arguments
+    /// on top of the stack. Errors are reported on the console like
+    /// [`dostring`] does; on success `nresults` values are left on the stack.
+    pub fn call(&self, nargs: c_int, nresults: c_int) -> c_int {
+        unsafe {
+            self.push();
+            lua_rotate(self.state, -(nargs + 1), 1); // lua_insert(state, -(nargs + 1))
+            report(self.state, pcall_traceback(self.state, nargs, nresults))
+        }
+    }
+}
+
+impl Drop for LuaRef {
+    fn drop(&mut self) {
+        unsafe { luaL_unref(self.state, LUA_REGISTRYINDEX, self.reference) };
+    }
+}
+
+pub unsafe fn report(state: *mut c_void, status: i32) -> i32 {
     if status != LUA_OK {
         unsafe {
             let mut len: c_long = 0;
             let msg = lua_tolstring(state, -1, &mut len);
             lua_writestring(msg, len as usize);
             lua_writeline();
-            lua_settop(state, -(1) - 1) // lua_pop(state, 1)
+
+            // defmt only gets the message, not the traceback that follows it
+            if !msg.is_null() {
+                let bytes = core::slice::from_raw_parts(msg, len as usize);
+                let first_line = bytes.split(|&b| b == b'\n').next().unwrap_or_default();
+                error!(
+                    "Lua error: {}",
+                    core::str::from_utf8(first_line).unwrap_or("<invalid UTF-8>")
+                );
+            }
+            lua_pop(state, 1);
         }
     }
     status
@@ -138,18 +924,30 @@ unsafe fn dostring(state: *mut c_void, script: &str, nret: i32) -> i32 {
     unsafe {
         let mut status = luaL_loadstring(state, script_as_cstring);
         if status == LUA_OK {
-            status = lua_pcall(state, 0, nret, 0);
+            status = pcall_traceback(state, 0, nret);
         }
         report(state, status)
     }
 }
 
+/// Check that each call, a function and its arguments like `"gpio.input, 40"`,
+/// fails with an error message containing the given text.
+unsafe fn assert_errors(state: *mut c_void, cases: &[(&str, &str)]) {
+    for (call, error) in cases {
+        let script = format!("assert(select(2, pcall({call})):find({error:?}, 1, true))");
+        my_assert!(unsafe { dostring(state, &script, 0) } == LUA_OK);
+    }
+}
+
 unsafe fn test_version(state: *mut c_void) {
     unsafe {
+        let guard = StackGuard::new(state);
         lua_getglobal(state, c"_VERSION".as_ptr());
-        let version = to_string(state, -1);
+        let version = to_string(state, -1).unwrap_or_default();
         my_assert!(version == "Lua 5.4");
         info!("{}", version);
+        lua_pop(state, 1);
+        guard.check();
     }
 }
 
@@ -159,15 +957,26 @@ unsafe fn test_exception(state: *mut c_void) {
     }
 
     unsafe {
+        let guard = StackGuard::new(state);
         lua_pushcclosure(state, it_panics, 0);
         let result = lua_pcall(state, 0, 0, 0);
         my_assert!(result == LUA_ERRRUN);
-        my_assert!(to_string(state, -1) == "exception!");
+        my_assert!(to_string(state, -1) == Some("exception!"));
+        lua_pop(state, 1);
+
+        lua_pushcclosure(state, it_panics, 0);
+        let result = pcall_traceback(state, 0, 0);
+        my_assert!(result == LUA_ERRRUN);
+        let msg = to_string(state, -1).unwrap_or_default();
+        my_assert!(msg.starts_with("exception!\nstack traceback:"));
+        lua_pop(state, 1);
+        guard.check();
     }
 }
 
 unsafe fn test_print(state: *mut c_void) {
     unsafe {
+        let guard = StackGuard::new(state);
         let script = "print(\"Hello World\")";
         let rv = dostring(state, script, 0);
         my_assert!(rv == LUA_OK);
@@ -196,33 +1005,932 @@ unsafe fn test_print(state: *mut c_void) {
         let fact_result = lua_tointegerx(state, -1, &mut isnum);
         my_assert!(isnum != 0);
         my_assert!(fact_result == 120);
-        lua_settop(state, -(1) - 1); // pop fact_result off of the stack
+        lua_pop(state, 1); // pop fact_result off of the stack
+        guard.check();
+    }
+}
+
+unsafe fn test_table(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let top = lua_gettop(state);
+
+        let readings = LuaTable::new(state, 3, 1);
+        my_assert!(readings.index() == top + 1 && readings.is_empty());
+        readings.set(1 as c_long, 21.5);
+        readings.set(2 as c_long, 22.0);
+        readings.set(3 as c_long, 22.5);
+        readings.set("unit", "C");
+        readings.set_global(c"readings");
+        my_assert!(readings.len() == 3 && !readings.is_empty());
+        my_assert!(readings.get::<_, String>("unit").as_deref() == Some("C"));
+        my_assert!(readings.get::<_, f64>("missing").is_none());
+        lua_settop(state, top);
+
+        let script = r#"
+            local sum = 0
+            for i = 1, #readings do sum = sum + readings[i] end
+            return { avg = sum / #readings, count = #readings, ok = true }
+        "#;
+        let rv = dostring(state, script, 1);
+        my_assert!(rv == LUA_OK);
+        let result = LuaTable::from_stack(state, -1).unwrap();
+        my_assert!(result.get::<_, f64>("avg") == Some(22.0));
+        my_assert!(result.get::<_, c_long>("count") == Some(3));
+        my_assert!(result.get::<_, bool>("ok") == Some(true));
+        my_assert!(result.pairs::<String, Skip>().count() == 3);
+        my_assert!(result.pairs::<String, bool>().count() == 1);
+        my_assert!(result.pairs::<String, Skip>().take(1).count() == 1);
+        my_assert!(result.get::<_, bool>("missing").is_none());
+        my_assert!(lua_gettop(state) == top + 1);
+        lua_settop(state, top);
+
+        lua_pushlstring(state, b"\xff".as_ptr(), 1);
+        my_assert!(String::from_lua(state, -1).is_none());
+        lua_settop(state, top);
+        guard.check();
+    }
+}
+
+unsafe fn test_userdata(state: *mut c_void) {
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+
+    struct Counter {
+        count: c_long,
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    unsafe extern "C-unwind" fn counter_add(state: *mut c_void) -> c_int {
+        unsafe {
+            let counter = check_userdata::<Counter>(state, 1);
+            counter.count += luaL_checkinteger(state, 2);
+            lua_pushinteger(state, counter.count);
+        }
+        1
+    }
+
+    impl LuaUserData for Counter {
+        const NAME: &'static CStr = c"test.Counter";
+        const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[(c"add", counter_add)];
+    }
+
+    unsafe {
+        let guard = StackGuard::new(state);
+        push_userdata(state, Counter { count: 0 });
+        lua_setglobal(state, c"counter".as_ptr());
+
+        let script = r#"
+            assert(counter:add(2) == 2 and counter:add(3) == 5)
+            assert(tostring(counter):find("^test.Counter: "))
+            assert(not pcall(counter.add, {}, 1))
+            counter = nil; collectgarbage()
+        "#;
+        let rv = dostring(state, script, 0);
+        my_assert!(rv == LUA_OK);
+        my_assert!(DROPPED.load(Ordering::Relaxed));
+        guard.check();
+    }
+}
+
+unsafe fn test_ref(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let script = r#"
+            local presses = 0
+            return function(n) presses = presses + n; return presses end
+        "#;
+        let rv = dostring(state, script, 1);
+        my_assert!(rv == LUA_OK);
+        let on_press = LuaRef::new(state);
+        let top = lua_gettop(state);
+
+        for (n, total) in [(1, 1), (2, 3)] {
+            lua_pushinteger(state, n);
+            my_assert!(on_press.call(1, 1) == LUA_OK);
+            my_assert!(c_long::from_lua(state, -1) == Some(total));
+            lua_settop(state, top);
+        }
+        guard.check();
+    }
+}
+
+async unsafe fn test_time(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        assert_errors(
+            state,
+            &[
+                ("time.every, 0, print", "time: bad period 0"),
+                ("time.every, -5, print", "time: bad period -5"),
+            ],
+        );
+        let script = r#"
+            local start = time.ticks_ms()
+            local fired, count = false, 0
+            time.after(20, function() fired = true end)
+            local every
+            every = time.every(5, function()
+                count = count + 1
+                if count == 3 then time.cancel(every) end
+            end)
+            time.sleep_ms(50)
+            assert(fired and count == 3)
+            assert(time.ticks_ms() - start >= 50)
+            assert(not time.cancel(every))
+            -- native calls yield through pcall too
+            assert(pcall(time.sleep_ms, 5))
+        "#;
+        let rv = crate::lua_async::AsyncLua::new(state).run(script).await;
+        my_assert!(rv == LUA_OK);
+        guard.check();
+    }
+}
+
+async unsafe fn test_tasks(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let rv = dostring(state, "assert(not pcall(task.spawn, print))", 0);
+        my_assert!(rv == LUA_OK);
+
+        let script = r#"
+            local log = {}
+            task.spawn(function()
+                for i = 1, 3 do log[#log + 1] = "a" .. i; task.yield() end
+            end, "a")
+            local b = task.spawn(function()
+                for i = 1, 3 do log[#log + 1] = "b" .. i; task.yield() end
+            end)
+            local got
+            task.spawn(function()
+                local ok, value = task.wait("go")
+                assert(ok)
+                got = value
+                assert(task.wait("never", 10) == false)
+            end, "waiter")
+
+            local status = {}
+            for _, t in ipairs(task.list()) do status[t.name] = t.status end
+            assert(status.main == "running" and status.a == "ready")
+            assert(status["task" .. b] == "ready" and status.waiter == "ready")
+
+            time.sleep_ms(5)
+            assert(table.concat(log, " ") == "a1 b1 a2 b2 a3 b3")
+            assert(task.signal("go", 42) == 1)
+            time.sleep_ms(5)
+            assert(got == 42)
+        "#;
+        let rv = crate::lua_async::AsyncLua::new(state).run(script).await;
+        my_assert!(rv == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_states(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let config = Config {
+            memory: 12 * 1024,
+            std_libs: &STD_LIBS[..1],
+            board_libs: &[(c"msg", crate::lua_msg::luaopen_msg)],
+            sandbox: None,
+        };
+        let Some(user) = LuaState::new(&config) else {
+            core::panic!("cannot open a user state");
+        };
+        let id = user.id();
+
+        let script = format!(
+            r#"
+            assert(msg.id() == 0 and not pcall(msg.send, {id}, print))
+            assert(msg.send({id}, {{ cmd = "add", args = {{ 1, 2.5 }} }}))
+            "#
+        );
+        my_assert!(dostring(state, &script, 0) == LUA_OK);
+        let call = format!("msg.send, {id}, {{string.rep('a', 1020)}}");
+        assert_errors(state, &[(&call, "msg: message over 1024 bytes")]);
+
+        let script = r#"
+            assert(table == nil and time == nil)
+            local m, from = msg.receive()
+            assert(from == 0 and m.cmd == "add")
+            assert(msg.send(from, m.args[1] + m.args[2]))
+        "#;
+        my_assert!(dostring(user.as_ptr(), script, 0) == LUA_OK);
+        let script = r#"
+            local big = {}
+            local ok, err = pcall(function()
+                while true do big[#big + 1] = {} end
+            end)
+            assert(not ok and err == "not enough memory")
+        "#;
+        my_assert!(dostring(user.as_ptr(), script, 0) == LUA_OK);
+        my_assert!(user.memory_used() <= config.memory);
+
+        let script = r#"
+            local sum, from = msg.receive()
+            assert(sum == 3.5 and msg.receive() == nil)
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        drop(user);
+        let script = format!("assert(not msg.send({id}, 1))");
+        my_assert!(dostring(state, &script, 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_sandbox() {
+    unsafe {
+        let config = Config {
+            memory: 24 * 1024,
+            std_libs: STD_LIBS,
+            board_libs: &[(c"msg", crate::lua_msg::luaopen_msg)],
+            sandbox: Some(Sandbox {
+                globals: SANDBOX_GLOBALS,
+                instructions: 100_000,
+            }),
+        };
+        let Some(user) = LuaState::new(&config) else {
+            core::panic!("cannot open a sandboxed state");
+        };
+        let state = user.as_ptr();
+        let guard = StackGuard::new(state);
+
+        let script = r#"
+            assert(io == nil and gpio == nil and _G == _ENV)
+            assert(msg and string.rep("a", 2) == "aa")
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        let script = r#"
+            assert(load("return 1")() == 1)
+            local f, err = load(string.dump(function() end))
+            assert(f == nil and err:find("binary"))
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+
+        let script = r#"
+            local ok, err = pcall(setmetatable, {}, {__gc = function() while true do end end})
+            assert(not ok and err:find("__gc not allowed") and setmetatable({}, {}))
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+
+        my_assert!(dostring(state, "while true do end", 0) == LUA_ERRRUN);
+        user.reset_instructions();
+        let script = "while true do pcall(function() while true do end end) end";
+        my_assert!(dostring(state, script, 0) == LUA_ERRRUN);
+        user.reset_instructions();
+        my_assert!(dostring(state, "assert(1 + 1 == 2)", 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_limits(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let script = r#"
+            return function(n) return n + 1 end,
+                function() while true do end end,
+                function() while true do pcall(function() while true do end end) end end
+        "#;
+        my_assert!(dostring(state, script, 3) == LUA_OK);
+        let limits = Limits {
+            instructions: Some(10_000),
+            time: None,
+        };
+
+        lua_pushvalue(state, -3);
+        lua_pushinteger(state, 1);
+        my_assert!(pcall_limited(state, 1, 1, &limits) == Ok(()));
+        my_assert!(c_long::from_lua(state, -1) == Some(2));
+        lua_pop(state, 1);
+
+        lua_pushvalue(state, -2);
+        my_assert!(pcall_limited(state, 0, 0, &limits) == Err(CallError::Instructions));
+        my_assert!(to_string(state, -1).is_some_and(|msg| msg.contains("budget exceeded")));
+        lua_pop(state, 1);
+
+        let limits = Limits {
+            instructions: None,
+            time: Some(Duration::from_millis(20)),
+        };
+        let start = Instant::now();
+        lua_pushvalue(state, -1);
+        my_assert!(pcall_limited(state, 0, 0, &limits) == Err(CallError::Time));
+        my_assert!(start.elapsed() >= Duration::from_millis(20));
+        lua_pop(state, 4);
+        guard.check();
     }
 }
 
 unsafe fn test_read(state: *mut c_void) {
     unsafe {
+        let guard = StackGuard::new(state);
         let script = r#"
             x = io.read(1)
             print(x)
         "#;
         let rv = dostring(state, script, 0);
         my_assert!(rv == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_os(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        crate::lua_os::set_env("LUA_TEST", "1");
+        let script = r#"
+            os.settime(1700000000)
+            assert(os.time() >= 1700000000 and os.clock() > 0)
+            assert(os.date("!%Y-%m-%d", 1700000000) == "2023-11-14")
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        let script = r#"
+            assert(os.getenv("LUA_TEST") == "1" and os.getenv("NONE") == nil)
+            local ok, err = os.rename("a", "b")
+            assert(not ok and err == "a: no filesystem")
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+fn test_crash() {
+    let report = Report::new(format_args!("a{}", "\u{e9}".repeat(100)), "src/lua.rs", 7);
+    my_assert!(report.is_valid());
+    // 128 bytes end in the middle of an é, which is dropped
+    my_assert!(report.message().len() == 127 && report.message().starts_with("a\u{e9}"));
+    my_assert!(report.file() == "src/lua.rs" && report.line() == 7);
+    my_assert!(report.stack().is_empty());
+
+    let file = "f".repeat(100);
+    let report = Report::new("", &file, 1);
+    my_assert!(report.message().is_empty() && report.file().len() == 64);
+}
+
+unsafe fn test_pio(state: *mut c_void) {
+    use crate::lua_pio::{Pins, Settings, check_program};
+
+    // pio_program(t) returns the length, origin and wrap of program t
+    unsafe extern "C-unwind" fn pio_program(state: *mut c_void) -> c_int {
+        unsafe {
+            let program = check_program(state, 1);
+            check_stack(state, 3);
+            lua_pushinteger(state, program.code.len() as c_long);
+            match program.origin {
+                Some(origin) => lua_pushinteger(state, origin.into()),
+                None => lua_pushnil(state),
+            }
+            lua_pushinteger(state, program.wrap.source.into());
+        }
+        3
+    }
+
+    unsafe {
+        let guard = StackGuard::new(state);
+        lua_pushcclosure(state, pio_program, 0);
+        lua_setglobal(state, c"pio_program".as_ptr());
+        let script = r#"
+            p = {origin = 0}
+            for i = 1, 32 do p[i] = 0xa042 end
+            local len, origin, wrap = pio_program(p)
+            assert(len == 32 and origin == 0 and wrap == 31)
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        let script = "p.origin = 1; assert(not pcall(pio_program, p)); p = nil";
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        for (program, error) in [
+            ("{}", "1..32"),
+            ("{0x10000}", "bad instruction 1"),
+            ("{0, wrap = 1}", "wrap"),
+            ("{0, sideset_bits = 5, sideset_opt = true}", "side"),
+        ] {
+            let script =
+                format!("assert(select(2, pcall(pio_program, {program})):find('{error}'))");
+            my_assert!(dostring(state, &script, 0) == LUA_OK);
+        }
+        lua_pushnil(state);
+        lua_setglobal(state, c"pio_program".as_ptr());
+
+        assert_errors(
+            state,
+            &[
+                (
+                    "pio.open, 2, 0, {0}, {}",
+                    "pio: no state machine 0 on block 2",
+                ),
+                ("pio.open, 0, 0, {0}", "pio: config table expected"),
+                (
+                    "pio.open, 0, 0, {0}, {set = 28, set_count = 3}",
+                    "set pins out of range",
+                ),
+                (
+                    "pio.open, 0, 0, {0}, {out = 2, out_count = 0}",
+                    "out needs 1..32 pins",
+                ),
+                (
+                    "pio.open, 0, 0, {0, sideset_bits = 1}, {}",
+                    "sideset pin needed",
+                ),
+                (
+                    "pio.open, 0, 0, {0}, {freq = 1}",
+                    "pio: clock divider out of range",
+                ),
+                (
+                    "pio.open, 0, 0, {0}, {join = 'both'}",
+                    "join must be \"tx\" or \"rx\"",
+                ),
+                (
+                    "pio.open, 0, 0, {0}, {shift_out = 'up'}",
+                    "shift must be \"left\"",
+                ),
+                (
+                    "pio.open, 0, 0, {0}, {autopull = 33}",
+                    "threshold must be 1..32 bits",
+                ),
+                (
+                    "pio.open, 0, 0, {0}, {set = 25}",
+                    "pio: gpio 25: pin in use",
+                ),
+            ],
+        );
+        my_assert!(dostring(state, "sm = pio.open(0, 0, {0xa042}, {})", 0) == LUA_OK);
+        assert_errors(
+            state,
+            &[("pio.open, 0, 0, {0}, {}", "pio: state machine in use")],
+        );
+        let script = "assert(sm:put(1) and sm:get() == nil); sm:close(); sm = nil";
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
+    }
+
+    let settings = Settings {
+        out: Some(Pins { base: 2, count: 3 }),
+        set: None,
+        sideset: Some(Pins { base: 10, count: 1 }),
+        in_base: Some(20),
+        jmp: Some(2),
+        divider: fixed::types::U24F8::from_num(1),
+        shift_out: (true, None),
+        shift_in: (true, None),
+        join: embassy_rp::pio::FifoJoin::Duplex,
+    };
+    my_assert!(settings.pin_mask() == 0b11100 | 1 << 10 | 1 << 20);
+}
+
+unsafe fn test_event(state: *mut c_void) {
+    use crate::lua_event::{Event, dispatch, post};
+
+    unsafe {
+        let guard = StackGuard::new(state);
+        let Some(own) = crate::lua_state::id(state) else {
+            core::panic!("not a LuaState");
+        };
+        let config = Config {
+            memory: 12 * 1024,
+            std_libs: &STD_LIBS[..1],
+            board_libs: &[],
+            sandbox: None,
+        };
+        let Some(other) = LuaState::new(&config) else {
+            core::panic!("cannot open a second state");
+        };
+
+        // the callback gpio.on_edge would register for pin 29
+        let script = "return function(pin, level) edge = pin * 2 + level end";
+        check_stack(state, 1);
+        luaL_getsubtable(state, LUA_REGISTRYINDEX, c"gpio.edge".as_ptr());
+        my_assert!(dostring(state, script, 1) == LUA_OK);
+        lua_rawseti(state, -2, 29);
+
+        // each state only sees its own events
+        my_assert!(post(
+            other.id(),
+            Event::Edge {
+                pin: 29,
+                high: true
+            }
+        ));
+        my_assert!(dispatch(state) == 0);
+        my_assert!(post(
+            own,
+            Event::Edge {
+                pin: 29,
+                high: false
+            }
+        ));
+        let script = "assert(event.poll() == 1 and edge == 58 and event.poll() == 0)";
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+
+        // and none are queued for a closed one
+        let id = other.id();
+        drop(other);
+        my_assert!(!post(
+            id,
+            Event::Edge {
+                pin: 29,
+                high: true
+            }
+        ));
+
+        lua_pushnil(state);
+        lua_rawseti(state, -2, 29);
+        lua_pop(state, 1);
+        my_assert!(dostring(state, "edge = nil", 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_pwm(state: *mut c_void) {
+    use crate::lua_pwm::{divider_and_top, duty_ticks};
+
+    let clk = embassy_rp::clocks::clk_sys_freq();
+    for (freq, phase_correct) in [(clk / 2, false), (1000, false), (1000, true), (10, false)] {
+        let Some((div, top)) = divider_and_top(freq, phase_correct) else {
+            core::panic!("no divider for {} Hz", freq);
+        };
+        my_assert!(top < u16::MAX);
+        let periods = if phase_correct { 2.0 } else { 1.0 };
+        let actual = f64::from(clk) / (div.to_num::<f64>() * (f64::from(top) + 1.0) * periods);
+        let within_1_percent = ((actual - f64::from(freq)) / f64::from(freq)).abs() < 0.01;
+        my_assert!(within_1_percent);
+    }
+    my_assert!(divider_and_top(0, false).is_none());
+    my_assert!(divider_and_top(1, false).is_none());
+    my_assert!(divider_and_top(clk, false).is_none());
+
+    for period in [2, 1000, 0xffff] {
+        my_assert!(duty_ticks(period, 100.0) == period as u16);
+        my_assert!(duty_ticks(period, 150.0) == period as u16);
+        my_assert!(duty_ticks(period, -5.0) == 0);
+    }
+    my_assert!(duty_ticks(1000, 25.0) == 250);
+
+    unsafe {
+        let guard = StackGuard::new(state);
+        let script = r#"
+            assert(select(2, pcall(pwm.open, 40)):find("pwm 40: no such pin"))
+            assert(select(2, pcall(pwm.open, 2, { freq = 0 })):find("0 Hz out of range"))
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_sys(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let scripts = [
+            r#"
+            local fw, executor = sys.version()
+            assert(fw:match("^%d+%.%d+%.%d+") and executor:match("^%d+%.%d+%.%d+"))
+            assert(sys.flash_id():match("^%x+$") and #sys.flash_id() == 16)
+            "#,
+            r#"
+            local used, peak, size = sys.heap()
+            assert(0 < used and used <= peak and peak <= size)
+            local c = sys.clocks()
+            assert(c.sys > 0 and c.peri > 0 and c.usb and c.adc and c.ref > 0)
+            "#,
+            r#"
+            local reasons = { ["power-on"] = true, watchdog = true, forced = true }
+            assert(reasons[sys.reset_reason()] and math.type(sys.uptime()) == "float")
+            "#,
+        ];
+        for script in scripts {
+            my_assert!(dostring(state, script, 0) == LUA_OK);
+        }
+        guard.check();
+    }
+}
+
+unsafe fn test_gpio(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        assert_errors(
+            state,
+            &[
+                ("gpio.input, 40", "gpio 40: no such pin"),
+                ("gpio.output, 25, 0", "gpio 25: pin in use"),
+                ("gpio.input, 2, 'sideways'", "invalid option 'sideways'"),
+                ("gpio.output, 2, 'high'", "number expected"),
+                ("gpio.on_edge, 2, 'rising', 5", "function expected"),
+            ],
+        );
+        let scripts = [
+            r#"
+            pin = gpio.input(2, "down")
+            assert(not pcall(gpio.input, 2) and not pcall(pin.set, pin, 1))
+            "#,
+            r#"
+            pin:close()
+            assert(select(2, pcall(pin.get, pin)):find("closed gpio.Pin"))
+            pin = nil
+            gpio.input(2):close()
+            "#,
+        ];
+        for script in scripts {
+            my_assert!(dostring(state, script, 0) == LUA_OK);
+        }
+        guard.check();
+    }
+}
+
+async unsafe fn test_edge_tasks() {
+    unsafe {
+        let config = Config {
+            memory: 24 * 1024,
+            std_libs: STD_LIBS,
+            board_libs: &[(c"gpio", crate::lua_gpio::luaopen_gpio)],
+            sandbox: None,
+        };
+        // more states than there are edge tasks, each closed while watching
+        for _ in 0..6 {
+            let Some(user) = LuaState::new(&config) else {
+                core::panic!("cannot open a user state");
+            };
+            let script = "gpio.on_edge(2, 'rising', function() end)";
+            my_assert!(dostring(user.as_ptr(), script, 0) == LUA_OK);
+            drop(user);
+            // let the stopped task hand pin 2 back
+            embassy_time::Timer::after_millis(1).await;
+        }
+    }
+}
+
+unsafe fn test_adc(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        my_assert!(dostring(state, "t = adc.open('temp')", 0) == LUA_OK);
+        assert_errors(
+            state,
+            &[
+                ("adc.open, 2", "adc: gpio 2 has no ADC channel"),
+                ("adc.open, 'hot'", "invalid option 'hot'"),
+                ("adc.open, 'temp'", "temperature sensor in use"),
+                ("sys.temperature", "temperature sensor not available"),
+                ("t.read, t, 0", "sample count must be 1..1024"),
+                ("t.burst, t, 0", "burst must be 1..4096 samples"),
+                ("t.burst, t, 1, 1", "rate 1 Hz too low"),
+                ("t.burst, t, 1, 0, 'csv'", "invalid option 'csv'"),
+            ],
+        );
+        let scripts = [
+            "assert(adc.volts(4096) == 3.3 and math.abs(adc.celsius(876.2) - 27) < 0.1)",
+            r#"
+            local c = t:celsius(4)
+            assert(c > -20 and c < 80 and #t:burst(4, 0, "string") == 8)
+            t:close(); t = nil
+            assert(sys.temperature())
+            "#,
+        ];
+        for script in scripts {
+            my_assert!(dostring(state, script, 0) == LUA_OK);
+        }
+        guard.check();
+    }
+}
+
+unsafe fn test_i2c(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        assert_errors(
+            state,
+            &[
+                ("i2c.open, 2, 4, 5", "i2c 2: gpio 4/5 are not its SDA/SCL"),
+                ("i2c.open, 0, 5, 4", "i2c 0: gpio 5/4 are not its SDA/SCL"),
+                ("i2c.open, 0, 4, 5, 1", "i2c: frequency 1 Hz out of range"),
+                (
+                    "i2c.open, 0, 4, 5, 1000001",
+                    "i2c: frequency 1000001 Hz out of range",
+                ),
+                ("i2c.open, 0, 0, 1", "i2c 0: pin in use"),
+            ],
+        );
+        my_assert!(dostring(state, "bus = i2c.open(0, 4, 5)", 0) == LUA_OK);
+        assert_errors(
+            state,
+            &[
+                ("i2c.open, 0, 8, 9", "i2c 0 in use"),
+                ("bus.read, bus, 0x80, 1", "i2c: bad address 128"),
+                ("bus.read, bus, 0x10, 0", "i2c: read must be 1..1024 bytes"),
+            ],
+        );
+        let script = "bus:close(); bus = nil; i2c.open(0, 4, 5):close()";
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
     }
 }
 
-pub fn test_lua() {
+unsafe fn test_spi(state: *mut c_void) {
     unsafe {
-        let state = luaL_newstate();
-        my_assert!(!state.is_null());
+        let guard = StackGuard::new(state);
+        assert_errors(
+            state,
+            &[
+                ("spi.open, 2, {}", "spi: no bus 2"),
+                ("spi.open, 0", "spi: options table expected"),
+                ("spi.open, 0, {mosi = 3}", "spi: sck pin required"),
+                (
+                    "spi.open, 0, {sck = 3, mosi = 3}",
+                    "spi 0: gpio 3 cannot be sck",
+                ),
+                (
+                    "spi.open, 1, {sck = 2, mosi = 3}",
+                    "spi 1: gpio 2 cannot be sck",
+                ),
+                (
+                    "spi.open, 0, {sck = 2, mosi = 3, mode = 4}",
+                    "spi: mode must be 0..3",
+                ),
+                (
+                    "spi.open, 0, {sck = 2, mosi = 3, order = 'mid'}",
+                    "order must be \"msb\"",
+                ),
+                (
+                    "spi.open, 0, {sck = 2, mosi = 3, freq = 1}",
+                    "frequency 1 Hz out of range",
+                ),
+                (
+                    "spi.open, 0, {sck = 18, mosi = 19, cs = 25}",
+                    "spi 0: gpio 25: pin in use",
+                ),
+            ],
+        );
+        my_assert!(dostring(state, "bus = spi.open(0, {sck = 18, mosi = 19})", 0) == LUA_OK);
+        assert_errors(
+            state,
+            &[
+                ("spi.open, 0, {sck = 2, mosi = 3}", "spi 0 in use"),
+                ("bus.read, bus, 1", "spi 0 has no miso pin"),
+                ("bus.select, bus", "spi 0 has no cs pin"),
+                ("bus.config, bus, {mode = 5}", "spi: mode must be 0..3"),
+            ],
+        );
+        let script = r#"
+            bus:write("\1\2"); bus:close(); bus = nil
+            spi.open(0, {sck = 18, mosi = 19, cs = 22}):close()
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_uart(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        assert_errors(
+            state,
+            &[
+                ("uart.open, 0, 1", "uart: gpio 0/1 are not UART1 TX/RX"),
+                ("uart.open, 4, 6", "uart: gpio 4/6 are not UART1 TX/RX"),
+                ("uart.open, 4, 5, {baud = 0}", "uart: bad baud rate 0"),
+                ("uart.open, 4, 5, {bits = 9}", "uart: bits must be 5..8"),
+                (
+                    "uart.open, 4, 5, {parity = 'mark'}",
+                    "parity must be none, even or odd",
+                ),
+                ("uart.open, 4, 5, {stop = 3}", "uart: stop must be 1 or 2"),
+                ("uart.open, 24, 25", "uart: pin in use"),
+            ],
+        );
+        my_assert!(dostring(state, "port = uart.open(4, 5, {baud = 9600})", 0) == LUA_OK);
+        assert_errors(
+            state,
+            &[
+                ("uart.open, 8, 9", "uart: UART1 in use"),
+                ("port.read, port, 0", "uart: read must be 1..1024 bytes"),
+            ],
+        );
+        // the RX pin floats, so what arrives is noise
+        let script = r#"
+            port:write("hello\r\n")
+            assert(math.type(port:available()) == "integer" and type(port:read(4)) == "string")
+            port:close(); port = nil
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_neopixel(state: *mut c_void) {
+    use crate::lua_neopixel::{shifts, word};
 
-        luaL_openlibs(state);
+    my_assert!(shifts("grb") == Some([16, 24, 8]));
+    my_assert!(shifts("rgb") == Some([24, 16, 8]));
+    for order in ["rrb", "gr", "grbw"] {
+        my_assert!(shifts(order).is_none());
+    }
+    my_assert!(word([16, 24, 8], 256, 0x112233) == 0x2211_3300);
+    my_assert!(word([24, 16, 8], 128, 0xff8000) == 0x7f40_0000);
+    my_assert!(word([16, 24, 8], 0, 0xffffff) == 0);
+
+    unsafe {
+        let guard = StackGuard::new(state);
+        assert_errors(
+            state,
+            &[
+                ("neopixel.open, 30", "neopixel: no gpio 30"),
+                (
+                    "neopixel.open, 16, {order = 'rgbw'}",
+                    "order must be like \"grb\"",
+                ),
+                (
+                    "neopixel.open, 16, {brightness = 2}",
+                    "brightness must be 0..1",
+                ),
+                ("neopixel.open, 25", "neopixel: gpio 25: pin in use"),
+            ],
+        );
+        my_assert!(dostring(state, "strip = neopixel.open(16)", 0) == LUA_OK);
+        assert_errors(
+            state,
+            &[
+                (
+                    "strip.write, strip, '\\1\\2'",
+                    "string must be 3 bytes a pixel",
+                ),
+                ("strip.write, strip, {1, 'x'}", "neopixel: bad colour at 2"),
+                (
+                    "strip.fill, strip, 0, 1025",
+                    "neopixel: at most 1024 pixels",
+                ),
+            ],
+        );
+        let script = r#"
+            assert(strip:brightness(0.5) == 0.5)
+            strip:write({0, 0}); strip:close(); strip = nil
+        "#;
+        my_assert!(dostring(state, script, 0) == LUA_OK);
+        guard.check();
+    }
+}
+
+unsafe fn test_watchdog(state: *mut c_void) {
+    use crate::lua_sys::script_fed;
+
+    unsafe {
+        let guard = StackGuard::new(state);
+        let later = |ms| Instant::now() + Duration::from_millis(ms);
+        my_assert!(script_fed(later(1_000_000)));
+        assert_errors(state, &[("sys.watchdog, 'soon'", "number expected")]);
+
+        my_assert!(dostring(state, "sys.watchdog(60000)", 0) == LUA_OK);
+        my_assert!(script_fed(later(0)) && !script_fed(later(60_001)));
+        my_assert!(dostring(state, "sys.feed()", 0) == LUA_OK);
+        my_assert!(script_fed(later(59_000)));
+
+        // disarmed again, nothing keeps it from being fed
+        my_assert!(dostring(state, "sys.watchdog(0)", 0) == LUA_OK);
+        my_assert!(script_fed(later(1_000_000)));
+        guard.check();
+    }
+}
+
+pub async fn test_lua() {
+    unsafe {
+        let config = Config {
+            memory: usize::MAX,
+            std_libs: STD_LIBS,
+            board_libs: BOARD_LIBS,
+            sandbox: None,
+        };
+        let Some(lua) = LuaState::new(&config) else {
+            core::panic!("cannot open a Lua state");
+        };
+        let state = lua.as_ptr();
 
         test_version(state);
         test_exception(state);
         test_print(state);
+        test_table(state);
+        test_userdata(state);
+        test_ref(state);
         test_read(state);
+        test_time(state).await;
+        test_tasks(state).await;
+        test_states(state);
+        test_sandbox();
+        test_limits(state);
+        test_os(state);
+        test_crash();
+        test_pio(state);
+        test_event(state);
+        test_pwm(state);
+        test_sys(state);
+        test_gpio(state);
+        test_edge_tasks().await;
+        test_adc(state);
+        test_i2c(state);
+        test_spi(state);
+        test_uart(state);
+        test_neopixel(state);
+        test_watchdog(state);
 
-        lua_close(state);
+        drop(lua);
     }
 }
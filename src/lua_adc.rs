@@ -0,0 +1,338 @@
+//! `adc` Lua library.
+//!
+//! ```lua
+//! local pot = adc.open(26)
+//! print(pot:read(), pot:volts(16))
+//! local samples = pot:burst(256, 10000)
+//! print(adc.open("temp"):celsius(8))
+//! ```
+//!
+//! Channels are GPIO26 to GPIO29, claimed from the [`pins`](crate::pins)
+//! registry, and the on-chip temperature sensor. All of them share the one
+//! converter handed to [`init`]; bursts are clocked out of its FIFO by DMA.
+
+use crate::lua::{
+    LUA_TNUMBER, LuaCFunction, LuaTable, LuaUserData, check_stack, check_userdata, close_userdata,
+    lua_pushinteger, lua_pushlstring, lua_pushnumber, lua_type, luaL_checkinteger,
+    luaL_checknumber, luaL_checkoption, luaL_error, luaL_optinteger, new_lib, push_buffer,
+    push_userdata,
+};
+use crate::pins;
+use core::cell::{Cell, RefCell};
+use core::ffi::{CStr, c_char, c_int, c_long, c_void};
+use embassy_rp::Peri;
+use embassy_rp::adc::{Adc, Async, Channel, Error};
+use embassy_rp::gpio::Pull;
+use embassy_rp::peripherals::{ADC_TEMP_SENSOR, DMA_CH2, PIN_26, PIN_27, PIN_28, PIN_29};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Full scale of the 12-bit converter.
+const ADC_MAX: f64 = 4096.0;
+/// ADC_VREF on the Pico is the 3.3 V rail.
+const VREF: f64 = 3.3;
+/// The converter runs from the 48 MHz USB PLL.
+const ADC_CLOCK: u32 = 48_000_000;
+/// Most samples `read`/`volts`/`celsius` will average.
+const MAX_AVERAGE: c_long = 1024;
+/// Most samples a single `burst` will take.
+const MAX_BURST: c_long = 4096;
+
+struct AdcState {
+    adc: Adc<'static, Async>,
+    dma: Peri<'static, DMA_CH2>,
+}
+
+static ADC: Mutex<CriticalSectionRawMutex, RefCell<Option<AdcState>>> =
+    Mutex::new(RefCell::new(None));
+
+// set while a script holds the temperature sensor channel
+static TEMP_OPEN: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Give the library the converter and the DMA channel for bursts.
+pub fn init(adc: Adc<'static, Async>, dma: Peri<'static, DMA_CH2>) {
+    ADC.lock(|cell| cell.replace(Some(AdcState { adc, dma })));
+}
+
+/// Run `f` on the converter. It is taken out of the mutex for the duration
+/// so a burst does not hold a critical section. `None` if there is no
+/// converter.
+fn with_adc<R>(f: impl FnOnce(&mut AdcState) -> R) -> Option<R> {
+    let mut state = ADC.lock(|cell| cell.take())?;
+    let result = f(&mut state);
+    ADC.lock(|cell| cell.replace(Some(state)));
+    Some(result)
+}
+
+fn volts(raw: f64) -> f64 {
+    raw * VREF / ADC_MAX
+}
+
+/// Temperature sensor reading to degrees C, per the RP2040 datasheet.
+fn celsius(raw: f64) -> f64 {
+    27.0 - (volts(raw) - 0.706) / 0.001721
+}
+
+/// Read the core temperature in degrees C, unless a script holds the sensor
+/// or the converter is missing.
+pub fn core_celsius() -> Option<f64> {
+    if TEMP_OPEN.lock(|open| open.replace(true)) {
+        return None;
+    }
+    // SAFETY: TEMP_OPEN was clear, so nobody else holds the sensor
+    let mut sensor = Channel::new_temp_sensor(unsafe { ADC_TEMP_SENSOR::steal() });
+    let raw = with_adc(|adc| adc.adc.blocking_read(&mut sensor).ok()).flatten();
+    TEMP_OPEN.lock(|open| open.set(false));
+    raw.map(|raw| celsius(f64::from(raw)))
+}
+
+/// An ADC input claimed by a script.
+pub struct LuaAdcChannel {
+    /// GPIO number, or `None` for the temperature sensor.
+    pin: Option<u8>,
+    channel: Channel<'static>,
+}
+
+impl Drop for LuaAdcChannel {
+    fn drop(&mut self) {
+        match self.pin {
+            Some(pin) => pins::release(pin),
+            None => TEMP_OPEN.lock(|open| open.set(false)),
+        }
+    }
+}
+
+impl LuaUserData for LuaAdcChannel {
+    const NAME: &'static CStr = c"adc.Channel";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"read", channel_read),
+        (c"volts", channel_volts),
+        (c"celsius", channel_celsius),
+        (c"burst", channel_burst),
+        (c"close", channel_close),
+    ];
+}
+
+/// Claim the channel for GPIO `number`, which must be one of the ADC pins.
+unsafe fn open_pin(state: *mut c_void, number: c_long) -> LuaAdcChannel {
+    unsafe {
+        if !(26..=29).contains(&number) {
+            luaL_error(
+                state,
+                c"adc: gpio %d has no ADC channel".as_ptr(),
+                number as c_int,
+            );
+        }
+        if let Err(e) = pins::claim(number as u8) {
+            luaL_error(
+                state,
+                c"adc %d: %s".as_ptr(),
+                number as c_int,
+                e.message().as_ptr(),
+            );
+        }
+        // SAFETY: the registry just handed this pin over
+        let channel = match number {
+            26 => Channel::new_pin(PIN_26::steal(), Pull::None),
+            27 => Channel::new_pin(PIN_27::steal(), Pull::None),
+            28 => Channel::new_pin(PIN_28::steal(), Pull::None),
+            _ => Channel::new_pin(PIN_29::steal(), Pull::None),
+        };
+        LuaAdcChannel {
+            pin: Some(number as u8),
+            channel,
+        }
+    }
+}
+
+unsafe fn open_temp(state: *mut c_void) -> LuaAdcChannel {
+    unsafe {
+        if TEMP_OPEN.lock(|open| open.replace(true)) {
+            luaL_error(state, c"adc: temperature sensor in use".as_ptr());
+        }
+        LuaAdcChannel {
+            pin: None,
+            // SAFETY: TEMP_OPEN was clear, so nobody else holds the sensor
+            channel: Channel::new_temp_sensor(ADC_TEMP_SENSOR::steal()),
+        }
+    }
+}
+
+/// `adc.open(pin | "temp")`
+unsafe extern "C-unwind" fn adc_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let channel = if lua_type(state, 1) == LUA_TNUMBER {
+            open_pin(state, luaL_checkinteger(state, 1))
+        } else {
+            let options: [*const c_char; 2] = [c"temp".as_ptr(), core::ptr::null()];
+            luaL_checkoption(state, 1, core::ptr::null(), options.as_ptr());
+            open_temp(state)
+        };
+        push_userdata(state, channel);
+    }
+    1
+}
+
+/// Mean of argument 2 (default 1) blocking reads on the channel at
+/// argument 1.
+unsafe fn average(state: *mut c_void) -> f64 {
+    unsafe {
+        let channel = check_userdata::<LuaAdcChannel>(state, 1);
+        let count = luaL_optinteger(state, 2, 1);
+        if !(1..=MAX_AVERAGE).contains(&count) {
+            luaL_error(
+                state,
+                c"adc: sample count must be 1..%d".as_ptr(),
+                MAX_AVERAGE as c_int,
+            );
+        }
+        let sum = with_adc(|adc| -> Result<u32, Error> {
+            let mut sum = 0;
+            for _ in 0..count {
+                sum += u32::from(adc.adc.blocking_read(&mut channel.channel)?);
+            }
+            Ok(sum)
+        });
+        match sum {
+            Some(Ok(sum)) => f64::from(sum) / count as f64,
+            Some(Err(_)) => {
+                luaL_error(state, c"adc: conversion failed".as_ptr());
+                core::unreachable!()
+            }
+            None => {
+                luaL_error(state, c"adc: converter not available".as_ptr());
+                core::unreachable!()
+            }
+        }
+    }
+}
+
+/// `channel:read([samples])` returns the raw 12-bit reading, averaged over
+/// `samples` conversions.
+unsafe extern "C-unwind" fn channel_read(state: *mut c_void) -> c_int {
+    unsafe {
+        let raw = average(state);
+        lua_pushinteger(state, (raw + 0.5) as c_long);
+    }
+    1
+}
+
+/// `channel:volts([samples])`
+unsafe extern "C-unwind" fn channel_volts(state: *mut c_void) -> c_int {
+    unsafe {
+        let raw = average(state);
+        lua_pushnumber(state, volts(raw));
+    }
+    1
+}
+
+/// `channel:celsius([samples])` for the temperature sensor.
+unsafe extern "C-unwind" fn channel_celsius(state: *mut c_void) -> c_int {
+    unsafe {
+        let raw = average(state);
+        lua_pushnumber(state, celsius(raw));
+    }
+    1
+}
+
+/// `channel:burst(samples [, rate_hz [, "table" | "string"]])`
+///
+/// Takes `samples` back-to-back conversions, at `rate_hz` or as fast as the
+/// converter goes (500 kS/s). Returns them as a table of integers, or as a
+/// string of little-endian 16-bit values.
+unsafe extern "C-unwind" fn channel_burst(state: *mut c_void) -> c_int {
+    unsafe {
+        let channel = check_userdata::<LuaAdcChannel>(state, 1);
+        let count = luaL_checkinteger(state, 2);
+        if !(1..=MAX_BURST).contains(&count) {
+            return luaL_error(
+                state,
+                c"adc: burst must be 1..%d samples".as_ptr(),
+                MAX_BURST as c_int,
+            );
+        }
+        let rate = luaL_optinteger(state, 3, 0);
+        // samples are taken every div + 1 cycles of the ADC clock; 0 runs
+        // the converter flat out
+        let div = if rate == 0 {
+            0
+        } else {
+            match u16::try_from((ADC_CLOCK / rate.max(1) as u32).saturating_sub(1)) {
+                Ok(div) => div,
+                Err(_) => {
+                    return luaL_error(state, c"adc: rate %d Hz too low".as_ptr(), rate as c_int);
+                }
+            }
+        };
+        let formats: [*const c_char; 3] =
+            [c"table".as_ptr(), c"string".as_ptr(), core::ptr::null()];
+        let as_string = luaL_checkoption(state, 4, c"table".as_ptr(), formats.as_ptr()) == 1;
+
+        // building the result may raise an error
+        check_stack(state, 3);
+        let len = count as usize;
+        let samples = push_buffer::<u16>(state, len);
+        let read = with_adc(|adc| {
+            embassy_futures::block_on(adc.adc.read_many(
+                &mut channel.channel,
+                samples,
+                div,
+                adc.dma.reborrow(),
+            ))
+        });
+        match read {
+            Some(Ok(())) => {}
+            Some(Err(_)) => return luaL_error(state, c"adc: conversion failed".as_ptr()),
+            None => return luaL_error(state, c"adc: converter not available".as_ptr()),
+        }
+
+        if as_string {
+            // the RP2040 is little-endian, so the buffer already is the string
+            lua_pushlstring(state, samples.as_ptr().cast(), len * size_of::<u16>());
+        } else {
+            let table = LuaTable::new(state, count as c_int, 0);
+            for (i, &sample) in samples.iter().enumerate() {
+                table.set(i as c_long + 1, c_long::from(sample));
+            }
+        }
+    }
+    1
+}
+
+/// `channel:close()` hands the pin or sensor back.
+unsafe extern "C-unwind" fn channel_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaAdcChannel>(state, 1) };
+    0
+}
+
+/// `adc.volts(raw)`
+unsafe extern "C-unwind" fn adc_volts(state: *mut c_void) -> c_int {
+    unsafe {
+        let raw = luaL_checknumber(state, 1);
+        lua_pushnumber(state, volts(raw));
+    }
+    1
+}
+
+/// `adc.celsius(raw)` converts a temperature sensor reading.
+unsafe extern "C-unwind" fn adc_celsius(state: *mut c_void) -> c_int {
+    unsafe {
+        let raw = luaL_checknumber(state, 1);
+        lua_pushnumber(state, celsius(raw));
+    }
+    1
+}
+
+pub unsafe extern "C-unwind" fn luaopen_adc(state: *mut c_void) -> c_int {
+    unsafe {
+        new_lib(
+            state,
+            &[
+                (c"open", adc_open),
+                (c"volts", adc_volts),
+                (c"celsius", adc_celsius),
+            ],
+        );
+    }
+    1
+}
@@ -0,0 +1,454 @@
+//! Running Lua chunks as coroutines on the embassy executor.
+//!
+//! [`AsyncLua::run`] runs a chunk as the first task of a scheduler that
+//! multiplexes Lua coroutines, the chunk's and those it starts with
+//! [`task.spawn`](crate::lua_task), over embassy timers and event sources.
+//!
+//! A native function that needs to await a Rust future (a UART read, a
+//! timer) hands it to [`await_future`], which yields the task; the
+//! scheduler polls the future alongside the other tasks' and resumes the
+//! task with the result. Everywhere else, say inside a callback, it blocks
+//! on the future instead. Tasks that are not held up by anything take turns
+//! between the yields, and timers and events are run whenever the tasks are
+//! all held up.
+
+use crate::lua::{
+    LUA_OK, LUA_YIELD, LuaCFunction, LuaRef, LuaUserData, check_stack, lua_gettop, lua_isyieldable,
+    lua_newthread, lua_pop, lua_pushboolean, lua_pushvalue, lua_rawequal, lua_resume, lua_rotate,
+    lua_settop, lua_tolstring, lua_xmove, lua_yieldk, luaL_error, luaL_loadbufferx, luaL_traceback,
+    registry_userdata, report,
+};
+use crate::{lua_event, lua_state, lua_time};
+use core::ffi::{CStr, c_char, c_int, c_long, c_void};
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::task::Poll;
+use embassy_futures::block_on;
+use embassy_futures::select::select3;
+use embassy_futures::yield_now;
+use embassy_time::{Instant, Timer};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Registry field holding the state's [`Scheduler`].
+const SCHEDULER: &CStr = c"async.scheduler";
+
+/// Finishes a native call with the output of its future: pushes the results
+/// onto the task's stack and returns how many there are.
+type Resume = Box<dyn FnOnce(*mut c_void) -> c_int>;
+type Wait = Pin<Box<dyn Future<Output = Resume>>>;
+
+/// What a task is held up by.
+enum Status {
+    Ready,
+    /// A future handed to [`await_future`].
+    Awaiting(Wait),
+    /// A `task.wait` for `event`, until `deadline` if there is one.
+    Waiting {
+        event: LuaRef,
+        deadline: Option<Instant>,
+    },
+}
+
+/// A coroutine run by the scheduler.
+struct Task {
+    id: c_long,
+    name: String,
+    thread: *mut c_void,
+    status: Status,
+    /// Values on top of the thread's stack to resume it with.
+    args: c_int,
+    /// How to finish the native call the task yielded in.
+    resume: Option<Resume>,
+    /// Keeps the thread from being collected. Declared last so that it is
+    /// dropped after references made on the thread's stack.
+    _anchor: LuaRef,
+}
+
+/// A state's tasks, kept in its registry.
+#[derive(Default)]
+struct Scheduler {
+    tasks: Vec<Task>,
+    last_id: c_long,
+    /// Whether an [`AsyncLua`] is running the tasks.
+    running: bool,
+    /// The thread of the task being resumed.
+    current: Option<*mut c_void>,
+    /// What the task being resumed yielded for; `None` for a plain yield.
+    yielded: Option<Status>,
+    /// How to finish the native call the task is being resumed into.
+    ready: Option<Resume>,
+}
+
+impl LuaUserData for Scheduler {
+    const NAME: &'static CStr = c"async.Scheduler";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[];
+}
+
+unsafe fn scheduler<'a>(state: *mut c_void) -> &'a mut Scheduler {
+    unsafe { registry_userdata(state, SCHEDULER) }
+}
+
+/// Whether `state` is a task the scheduler is resuming, and so may yield to
+/// it.
+unsafe fn in_task(state: *mut c_void) -> bool {
+    unsafe { scheduler(state).current == Some(state) && lua_isyieldable(state) != 0 }
+}
+
+/// Add the thread on top of `state`'s stack, with its function already on
+/// its own stack, as a task. Pops the thread and returns the task's id.
+unsafe fn add_task(state: *mut c_void, thread: *mut c_void, name: Option<&[u8]>) -> c_long {
+    unsafe {
+        // the thread anchors itself, so the reference outlives any task
+        // that spawned it
+        check_stack(thread, 1);
+        lua_xmove(state, thread, 1);
+        let anchor = LuaRef::new(thread);
+        let scheduler = scheduler(state);
+        scheduler.last_id = scheduler.last_id.wrapping_add(1).max(1);
+        let id = scheduler.last_id;
+        let name = match name {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => format!("task{id}"),
+        };
+        scheduler.tasks.push(Task {
+            id,
+            name,
+            thread,
+            status: Status::Ready,
+            args: 0,
+            resume: None,
+            _anchor: anchor,
+        });
+        id
+    }
+}
+
+/// Start the function on top of the stack as a task, named `name` or after
+/// its id. Pops the function and returns the id, or `None`, leaving the
+/// function where it is, if no scheduler is running.
+pub unsafe fn spawn(state: *mut c_void, name: Option<&[u8]>) -> Option<c_long> {
+    unsafe {
+        if !scheduler(state).running {
+            return None;
+        }
+        check_stack(state, 1);
+        let thread = lua_newthread(state);
+        lua_rotate(state, -2, 1);
+        lua_xmove(state, thread, 1);
+        Some(add_task(state, thread, name))
+    }
+}
+
+/// Let the other tasks run before carrying on. Outside a task there are
+/// none to let run, so this returns at once.
+pub unsafe fn yield_task(state: *mut c_void) -> c_int {
+    unsafe {
+        if in_task(state) {
+            return lua_yieldk(state, 0, 0, None);
+        }
+    }
+    0
+}
+
+/// Continuation of `task.wait`: returns the values the task was resumed
+/// with, which sit above the `ctx` values the call had.
+unsafe extern "C-unwind" fn wait_k(state: *mut c_void, _status: c_int, ctx: isize) -> c_int {
+    unsafe { lua_gettop(state) - ctx as c_int }
+}
+
+/// Hold the task up until [`signal`] is called with the event at `event`,
+/// then return `true` and the signalled values; or until `deadline`, then
+/// return `false`.
+pub unsafe fn wait(state: *mut c_void, event: c_int, deadline: Option<Instant>) -> c_int {
+    unsafe {
+        if !in_task(state) {
+            return luaL_error(state, c"task: cannot wait outside a task".as_ptr());
+        }
+        let event = LuaRef::from_stack(state, event);
+        scheduler(state).yielded = Some(Status::Waiting { event, deadline });
+        lua_yieldk(state, 0, lua_gettop(state) as isize, Some(wait_k))
+    }
+}
+
+/// Make the tasks waiting for the event at `event` ready, to be resumed with
+/// `true` and the `count` values on top of the stack, which stay there.
+/// Returns how many tasks were waiting.
+pub unsafe fn signal(state: *mut c_void, event: c_int, count: c_int) -> usize {
+    let mut woken = 0;
+    unsafe {
+        let top = lua_gettop(state);
+        for task in scheduler(state).tasks.iter_mut() {
+            let Status::Waiting { event: waited, .. } = &task.status else {
+                continue;
+            };
+            let thread = task.thread;
+            check_stack(thread, count + 2);
+            check_stack(state, count.max(1));
+            waited.push();
+            lua_pushvalue(state, event);
+            lua_xmove(state, thread, 1);
+            let same = lua_rawequal(thread, -1, -2) != 0;
+            lua_pop(thread, 2);
+            if !same {
+                continue;
+            }
+            lua_pushboolean(thread, 1);
+            for i in 0..count {
+                lua_pushvalue(state, top - count + 1 + i);
+            }
+            lua_xmove(state, thread, count);
+            task.args = count + 1;
+            task.status = Status::Ready;
+            woken += 1;
+        }
+    }
+    woken
+}
+
+/// One line per task: id, name and what it is doing.
+pub unsafe fn list(state: *mut c_void) -> Vec<(c_long, String, &'static str)> {
+    let scheduler = unsafe { scheduler(state) };
+    let current = scheduler.current;
+    scheduler
+        .tasks
+        .iter()
+        .map(|task| {
+            let status = match task.status {
+                _ if current == Some(task.thread) => "running",
+                Status::Ready => "ready",
+                Status::Awaiting(_) => "awaiting",
+                Status::Waiting { .. } => "waiting",
+            };
+            (task.id, task.name.clone(), status)
+        })
+        .collect()
+}
+
+/// Continuation of a native function that yielded in [`await_future`].
+unsafe extern "C-unwind" fn resume_k(state: *mut c_void, _status: c_int, _ctx: isize) -> c_int {
+    unsafe {
+        match scheduler(state).ready.take() {
+            Some(resume) => resume(state),
+            None => 0,
+        }
+    }
+}
+
+/// Await `future` from a native function, then return `resume` with its
+/// output. Call it in tail position, as in
+/// `return await_future(state, Timer::after_millis(5), |_, ()| 0)`.
+///
+/// Nothing borrowed from the Lua stack may be held across the wait: it is
+/// only safe to look at again inside `resume`.
+pub unsafe fn await_future<F, R>(state: *mut c_void, future: F, resume: R) -> c_int
+where
+    F: Future + 'static,
+    R: FnOnce(*mut c_void, F::Output) -> c_int + 'static,
+{
+    unsafe {
+        if !in_task(state) {
+            return resume(state, block_on(future));
+        }
+        scheduler(state).yielded = Some(Status::Awaiting(Box::pin(async move {
+            let output = future.await;
+            Box::new(move |state| resume(state, output)) as Resume
+        })));
+        lua_yieldk(state, 0, 0, Some(resume_k))
+    }
+}
+
+/// Runs chunks on a Lua state as coroutines.
+pub struct AsyncLua {
+    state: *mut c_void,
+}
+
+impl AsyncLua {
+    /// # Safety
+    ///
+    /// `state` must stay open for as long as the runner is used, and not
+    /// be used by anything else while [`run`](Self::run) is awaited.
+    pub unsafe fn new(state: *mut c_void) -> Self {
+        Self { state }
+    }
+
+    /// Resume the task `id` once. Returns its status if it finished, after
+    /// reporting any error it raised.
+    unsafe fn step(&self, id: c_long) -> Option<c_int> {
+        let state = self.state;
+        unsafe {
+            let scheduler = scheduler(state);
+            let task = scheduler.tasks.iter_mut().find(|task| task.id == id)?;
+            let thread = task.thread;
+            let args = core::mem::take(&mut task.args);
+            scheduler.ready = task.resume.take();
+            scheduler.current = Some(thread);
+
+            let mut results = 0;
+            let status = lua_resume(thread, state, args, &mut results);
+
+            // tasks may have been spawned, but none removed
+            let scheduler = self::scheduler(state);
+            scheduler.current = None;
+            scheduler.ready = None;
+            let yielded = scheduler.yielded.take();
+            let i = scheduler.tasks.iter().position(|task| task.id == id)?;
+            if status == LUA_YIELD {
+                lua_pop(thread, results);
+                scheduler.tasks[i].status = yielded.unwrap_or(Status::Ready);
+                return None;
+            }
+            if status != LUA_OK {
+                // the dead coroutine still has the stack the error was
+                // raised on
+                let msg = lua_tolstring(thread, -1, core::ptr::null_mut());
+                let msg = if msg.is_null() {
+                    c"(error object is not a string)".as_ptr()
+                } else {
+                    msg
+                };
+                check_stack(state, 1);
+                luaL_traceback(state, thread, msg, 0);
+                report(state, status);
+            }
+            scheduler.tasks.remove(i);
+            Some(status)
+        }
+    }
+
+    /// Make the tasks whose `task.wait` has run out of time ready, to be
+    /// resumed with `false`.
+    unsafe fn time_out(&self, now: Instant) {
+        for task in unsafe { scheduler(self.state) }.tasks.iter_mut() {
+            let Status::Waiting {
+                deadline: Some(deadline),
+                ..
+            } = task.status
+            else {
+                continue;
+            };
+            if deadline <= now {
+                unsafe {
+                    check_stack(task.thread, 1);
+                    lua_pushboolean(task.thread, 0);
+                }
+                task.args = 1;
+                task.status = Status::Ready;
+            }
+        }
+    }
+
+    /// Poll the futures tasks are awaiting, making the tasks whose future is
+    /// done ready. Ready once there is such a task.
+    fn poll_awaiting(&self, cx: &mut core::task::Context<'_>) -> Poll<()> {
+        let mut done = false;
+        for task in unsafe { scheduler(self.state) }.tasks.iter_mut() {
+            let Status::Awaiting(wait) = &mut task.status else {
+                continue;
+            };
+            if let Poll::Ready(resume) = wait.as_mut().poll(cx) {
+                task.resume = Some(resume);
+                task.status = Status::Ready;
+                done = true;
+            }
+        }
+        if done { Poll::Ready(()) } else { Poll::Pending }
+    }
+
+    /// Run the tasks until all have finished and no timers are left.
+    /// Returns the status of the task `main`.
+    async fn serve(&self, main: c_long) -> c_int {
+        let state = self.state;
+        let events = unsafe { lua_state::id(state) };
+        let mut status = LUA_OK;
+        loop {
+            unsafe {
+                lua_time::fire_due(state);
+                lua_event::dispatch(state);
+                self.time_out(Instant::now());
+            }
+
+            let ready: Vec<c_long> = unsafe { scheduler(state) }
+                .tasks
+                .iter()
+                .filter(|task| matches!(task.status, Status::Ready))
+                .map(|task| task.id)
+                .collect();
+            for id in ready {
+                match unsafe { self.step(id) } {
+                    Some(finished) if id == main => status = finished,
+                    _ => {}
+                }
+            }
+
+            let scheduler = unsafe { scheduler(state) };
+            let next_timer = unsafe { lua_time::next_due(state) };
+            if scheduler.tasks.is_empty() && next_timer.is_none() {
+                return status;
+            }
+            if scheduler
+                .tasks
+                .iter()
+                .any(|task| matches!(task.status, Status::Ready))
+            {
+                // spawned, yielded or signalled: let other embassy tasks
+                // run before their turn
+                yield_now().await;
+                continue;
+            }
+            let deadline = scheduler
+                .tasks
+                .iter()
+                .filter_map(|task| match task.status {
+                    Status::Waiting { deadline, .. } => deadline,
+                    _ => None,
+                })
+                .chain(next_timer)
+                .min()
+                .unwrap_or(Instant::MAX);
+            select3(
+                poll_fn(|cx| self.poll_awaiting(cx)),
+                Timer::at(deadline),
+                lua_event::wait(events),
+            )
+            .await;
+        }
+    }
+
+    /// Run `script` as a task. Returns once it and every task it spawned
+    /// have finished and the timers have run out, with the script's status.
+    /// Errors are reported on the console like
+    /// [`LuaRef::call`](crate::lua::LuaRef::call) does.
+    pub async fn run(&self, script: &str) -> c_int {
+        let state = self.state;
+        unsafe {
+            let top = lua_gettop(state);
+            check_stack(state, 1);
+            let thread = lua_newthread(state);
+            let status = luaL_loadbufferx(
+                thread,
+                script.as_ptr() as *const c_char,
+                script.len(),
+                c"=script".as_ptr(),
+                // source only: bytecode can crash the VM
+                c"t".as_ptr(),
+            );
+            if status != LUA_OK {
+                report(thread, status);
+                lua_settop(state, top);
+                return status;
+            }
+
+            let main = add_task(state, thread, Some(b"main"));
+            scheduler(state).running = true;
+            let status = self.serve(main).await;
+            scheduler(state).running = false;
+            lua_settop(state, top);
+            status
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! `event` Lua library: hands events raised by Rust tasks to Lua callbacks.
+//!
+//! Drivers [`post`] an [`Event`] from any task, including ones running on an
+//! interrupt executor while a script is busy. Each
+//! [`LuaState`](crate::lua_state::LuaState) has a queue of [`QUEUE`] events,
+//! and a driver posts to the state whose script asked for the event. The
+//! callbacks run on the Lua side when a script calls `event.poll()`, or
+//! between statements once [`install_dispatch_hook`] has been called for the
+//! state.
+
+use crate::lua::{LUA_MASKCOUNT, lua_gethook, lua_pushinteger, lua_sethook, new_lib};
+use crate::lua_gpio;
+use crate::lua_state::{self, MAX_STATES};
+use core::ffi::{c_int, c_void};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+/// VM instructions between checks of the queue by the dispatch hook.
+pub const HOOK_INTERVAL: c_int = 1000;
+/// Events a state can have waiting.
+pub const QUEUE: usize = 16;
+
+pub enum Event {
+    /// A watched GPIO saw the edge it was registered for.
+    Edge { pin: u8, high: bool },
+}
+
+static EVENTS: [Channel<CriticalSectionRawMutex, Event, QUEUE>; MAX_STATES] =
+    [const { Channel::new() }; MAX_STATES];
+
+/// Queue `event` for the state with id `to`. Returns `false`, dropping the
+/// event, if that state is not open or its queue is full.
+pub fn post(to: u8, event: Event) -> bool {
+    lua_state::is_open(to) && EVENTS[to as usize].try_send(event).is_ok()
+}
+
+/// Wait until there is an event for the state with id `id` to dispatch.
+/// A state without an id gets no events, so for `None` this never returns.
+pub async fn wait(id: Option<u8>) {
+    match id {
+        Some(id) => EVENTS[id as usize].ready_to_receive().await,
+        None => core::future::pending().await,
+    }
+}
+
+/// Drop the events waiting for state `id`, e.g. when it closes.
+pub fn clear(id: u8) {
+    while EVENTS[id as usize].try_receive().is_ok() {}
+}
+
+/// Run the callbacks for every event queued for `state`. Returns how many
+/// were handled.
+pub unsafe fn dispatch(state: *mut c_void) -> usize {
+    let Some(id) = (unsafe { lua_state::id(state) }) else {
+        return 0;
+    };
+    let mut handled = 0;
+    while let Ok(event) = EVENTS[id as usize].try_receive() {
+        match event {
+            Event::Edge { pin, high } => unsafe { lua_gpio::dispatch_edge(state, pin, high) },
+        }
+        handled += 1;
+    }
+    handled
+}
+
+unsafe extern "C-unwind" fn dispatch_hook(state: *mut c_void, _ar: *mut c_void) {
+    unsafe { dispatch(state) };
+}
+
+/// Dispatch events every [`HOOK_INTERVAL`] instructions while `state` runs
+/// Lua code, without the script having to call `event.poll()`. A state
+/// that already has a count hook, like a sandboxed one, keeps it: that hook
+/// dispatches too.
+pub unsafe fn install_dispatch_hook(state: *mut c_void) {
+    unsafe {
+        if lua_gethook(state).is_none() {
+            lua_sethook(state, Some(dispatch_hook), LUA_MASKCOUNT, HOOK_INTERVAL);
+        }
+    }
+}
+
+/// `event.poll()` runs pending callbacks and returns how many ran.
+unsafe extern "C-unwind" fn event_poll(state: *mut c_void) -> c_int {
+    unsafe {
+        let handled = dispatch(state);
+        lua_pushinteger(state, handled as _);
+    }
+    1
+}
+
+pub unsafe extern "C-unwind" fn luaopen_event(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"poll", event_poll)]) };
+    1
+}
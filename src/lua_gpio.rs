@@ -0,0 +1,390 @@
+//! `gpio` Lua library.
+//!
+//! ```lua
+//! local led = gpio.output(15, 1)
+//! led:toggle()
+//! local button = gpio.input(14, "up")
+//! print(button:get())
+//!
+//! gpio.on_edge(14, "falling", function(pin, level) print("pressed") end, "up", 20)
+//! ```
+//!
+//! Pins come from the [`pins`](crate::pins) registry and go back to it when
+//! the object is closed or collected, or when `gpio.off_edge` stops watching.
+//!
+//! Edges are awaited by [`edge_task`]s on the spawner given to [`init`], which
+//! should be an interrupt executor so they keep running while a script is
+//! busy. They [`post`](crate::lua_event::post) events to the state that set
+//! the callback, and its `event` library hands them to the callbacks. Closing
+//! the state stops its edge tasks.
+
+use crate::lua::{
+    LUA_REGISTRYINDEX, LUA_TBOOLEAN, LUA_TFUNCTION, LuaCFunction, LuaUserData, StackGuard,
+    check_stack, check_userdata, close_userdata, lua_gettop, lua_pushinteger, lua_pushnil,
+    lua_pushvalue, lua_rawgeti, lua_rawseti, lua_settop, lua_toboolean, lua_type,
+    luaL_checkinteger, luaL_checkoption, luaL_checktype, luaL_error, luaL_getsubtable,
+    luaL_optinteger, new_lib, pcall_traceback, push_userdata, report,
+};
+use crate::lua_event::{self, Event};
+use crate::lua_state;
+use crate::pins::{self, PIN_COUNT};
+use core::cell::Cell;
+use core::ffi::{CStr, c_char, c_int, c_void};
+use defmt::*;
+use embassy_executor::SendSpawner;
+use embassy_futures::select::{Either, select};
+use embassy_rp::Peri;
+use embassy_rp::gpio::{AnyPin, Flex, Input, Level, Pull};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+/// Registry table mapping a watched pin number to its Lua callback.
+const EDGE_CALLBACKS: &CStr = c"gpio.edge";
+
+static EDGE_SPAWNER: Mutex<CriticalSectionRawMutex, Cell<Option<SendSpawner>>> =
+    Mutex::new(Cell::new(None));
+
+// tells the edge task watching a pin to stop and release it
+static EDGE_STOP: [Signal<CriticalSectionRawMutex, ()>; PIN_COUNT as usize] =
+    [const { Signal::new() }; PIN_COUNT as usize];
+
+// id of the state each watched pin posts its edges to
+static EDGE_OWNERS: Mutex<CriticalSectionRawMutex, Cell<[Option<u8>; PIN_COUNT as usize]>> =
+    Mutex::new(Cell::new([None; PIN_COUNT as usize]));
+
+/// Give the library the spawner for its edge tasks.
+pub fn init(spawner: SendSpawner) {
+    EDGE_SPAWNER.lock(|cell| cell.set(Some(spawner)));
+}
+
+fn set_edge_owner(pin: u8, owner: Option<u8>) {
+    EDGE_OWNERS.lock(|cell| {
+        let mut owners = cell.get();
+        owners[pin as usize] = owner;
+        cell.set(owners);
+    });
+}
+
+/// Stop the edge tasks posting to the state with id `owner`, which is being
+/// closed. Their pins go back to the registry once the tasks have run.
+pub fn stop_edges(owner: u8) {
+    let owners = EDGE_OWNERS.lock(|cell| cell.get());
+    for (pin, _) in owners
+        .iter()
+        .enumerate()
+        .filter(|(_, o)| **o == Some(owner))
+    {
+        EDGE_STOP[pin].signal(());
+    }
+}
+
+/// A GPIO claimed by a script.
+pub struct LuaPin {
+    number: u8,
+    flex: Flex<'static>,
+    output: bool,
+}
+
+impl Drop for LuaPin {
+    fn drop(&mut self) {
+        pins::release(self.number);
+    }
+}
+
+impl LuaUserData for LuaPin {
+    const NAME: &'static CStr = c"gpio.Pin";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"set", pin_set),
+        (c"get", pin_get),
+        (c"toggle", pin_toggle),
+        (c"close", pin_close),
+    ];
+}
+
+/// Claim the pin numbered by argument `arg`, raising a Lua error if the
+/// registry refuses it.
+unsafe fn claim_pin(state: *mut c_void, arg: c_int) -> (u8, Peri<'static, AnyPin>) {
+    unsafe {
+        let number = luaL_checkinteger(state, arg);
+        match u8::try_from(number)
+            .map_err(|_| pins::PinError::Invalid)
+            .and_then(pins::claim)
+        {
+            Ok(pin) => (number as u8, pin),
+            Err(e) => {
+                luaL_error(
+                    state,
+                    c"gpio %d: %s".as_ptr(),
+                    number as c_int,
+                    e.message().as_ptr(),
+                );
+                core::unreachable!()
+            }
+        }
+    }
+}
+
+/// A level is either a boolean or a number, where anything but 0 is high.
+unsafe fn check_level(state: *mut c_void, arg: c_int) -> Level {
+    unsafe {
+        let high = if lua_type(state, arg) == LUA_TBOOLEAN {
+            lua_toboolean(state, arg) != 0
+        } else {
+            luaL_checkinteger(state, arg) != 0
+        };
+        Level::from(high)
+    }
+}
+
+/// Optional argument `arg`: `"none"` (the default), `"up"` or `"down"`.
+unsafe fn check_pull(state: *mut c_void, arg: c_int) -> Pull {
+    let options: [*const c_char; 4] = [
+        c"none".as_ptr(),
+        c"up".as_ptr(),
+        c"down".as_ptr(),
+        core::ptr::null(),
+    ];
+    match unsafe { luaL_checkoption(state, arg, c"none".as_ptr(), options.as_ptr()) } {
+        1 => Pull::Up,
+        2 => Pull::Down,
+        _ => Pull::None,
+    }
+}
+
+unsafe fn check_output<'a>(state: *mut c_void, arg: c_int) -> &'a mut LuaPin {
+    unsafe {
+        let pin = check_userdata::<LuaPin>(state, arg);
+        if !pin.output {
+            luaL_error(
+                state,
+                c"gpio %d is not an output".as_ptr(),
+                pin.number as c_int,
+            );
+        }
+        pin
+    }
+}
+
+/// `gpio.output(pin, level)`
+unsafe extern "C-unwind" fn gpio_output(state: *mut c_void) -> c_int {
+    unsafe {
+        let level = check_level(state, 2);
+        let (number, pin) = claim_pin(state, 1);
+        let mut flex = Flex::new(pin);
+        flex.set_level(level);
+        flex.set_as_output();
+        push_userdata(
+            state,
+            LuaPin {
+                number,
+                flex,
+                output: true,
+            },
+        );
+    }
+    1
+}
+
+/// `gpio.input(pin [, "none" | "up" | "down"])`
+unsafe extern "C-unwind" fn gpio_input(state: *mut c_void) -> c_int {
+    unsafe {
+        let pull = check_pull(state, 2);
+        let (number, pin) = claim_pin(state, 1);
+        let mut flex = Flex::new(pin);
+        flex.set_pull(pull);
+        flex.set_as_input();
+        push_userdata(
+            state,
+            LuaPin {
+                number,
+                flex,
+                output: false,
+            },
+        );
+    }
+    1
+}
+
+/// `pin:set(level)`
+unsafe extern "C-unwind" fn pin_set(state: *mut c_void) -> c_int {
+    unsafe {
+        let level = check_level(state, 2);
+        check_output(state, 1).flex.set_level(level);
+    }
+    0
+}
+
+/// `pin:get()` returns 0 or 1: the input level, or the level being driven
+/// for an output.
+unsafe extern "C-unwind" fn pin_get(state: *mut c_void) -> c_int {
+    unsafe {
+        let pin = check_userdata::<LuaPin>(state, 1);
+        let high = if pin.output {
+            pin.flex.is_set_high()
+        } else {
+            pin.flex.is_high()
+        };
+        lua_pushinteger(state, high as _);
+    }
+    1
+}
+
+/// `pin:toggle()`
+unsafe extern "C-unwind" fn pin_toggle(state: *mut c_void) -> c_int {
+    unsafe { check_output(state, 1).flex.toggle() };
+    0
+}
+
+/// `pin:close()` hands the pin back to the registry.
+unsafe extern "C-unwind" fn pin_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaPin>(state, 1) };
+    0
+}
+
+#[derive(Clone, Copy)]
+enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// Waits for edges on one pin and posts them as events to the state with id
+/// `owner` until told to stop, then hands the pin back to the registry.
+#[embassy_executor::task(pool_size = 4)]
+async fn edge_task(owner: u8, pin: u8, mut input: Input<'static>, edge: Edge, debounce: Duration) {
+    let stop = &EDGE_STOP[pin as usize];
+    loop {
+        let wait = async {
+            match edge {
+                Edge::Rising => input.wait_for_rising_edge().await,
+                Edge::Falling => input.wait_for_falling_edge().await,
+                Edge::Both => input.wait_for_any_edge().await,
+            }
+        };
+        if let Either::Second(()) = select(wait, stop.wait()).await {
+            break;
+        }
+
+        if debounce.as_ticks() > 0 {
+            Timer::after(debounce).await;
+        }
+        let high = input.is_high();
+        // drop edges that bounced back before the debounce time was up
+        let settled = match edge {
+            Edge::Rising => high,
+            Edge::Falling => !high,
+            Edge::Both => true,
+        };
+        if settled && !lua_event::post(owner, Event::Edge { pin, high }) {
+            warn!("gpio {}: event queue full, edge dropped", pin);
+        }
+    }
+    drop(input);
+    set_edge_owner(pin, None);
+    pins::release(pin);
+}
+
+/// `gpio.on_edge(pin, "rising" | "falling" | "both", fn [, pull [, debounce_ms]])`
+///
+/// Calls `fn(pin, level)` for each edge until `gpio.off_edge(pin)`.
+unsafe extern "C-unwind" fn gpio_on_edge(state: *mut c_void) -> c_int {
+    unsafe {
+        let options: [*const c_char; 4] = [
+            c"rising".as_ptr(),
+            c"falling".as_ptr(),
+            c"both".as_ptr(),
+            core::ptr::null(),
+        ];
+        let edge = match luaL_checkoption(state, 2, core::ptr::null(), options.as_ptr()) {
+            0 => Edge::Rising,
+            1 => Edge::Falling,
+            _ => Edge::Both,
+        };
+        luaL_checktype(state, 3, LUA_TFUNCTION);
+        let pull = check_pull(state, 4);
+        let debounce = luaL_optinteger(state, 5, 0).max(0) as u64;
+        let Some(spawner) = EDGE_SPAWNER.lock(|cell| cell.get()) else {
+            return luaL_error(state, c"gpio: edge events not available".as_ptr());
+        };
+        let Some(owner) = lua_state::id(state) else {
+            return luaL_error(state, c"gpio: not a LuaState".as_ptr());
+        };
+
+        let (number, pin) = claim_pin(state, 1);
+        let input = Input::new(pin, pull);
+        // before the spawn, so that a stop sent before the task first runs
+        // is not lost
+        EDGE_STOP[number as usize].reset();
+        set_edge_owner(number, Some(owner));
+        let task = edge_task(owner, number, input, edge, Duration::from_millis(debounce));
+        if spawner.spawn(task).is_err() {
+            // the input went down with the unspawned task
+            set_edge_owner(number, None);
+            pins::release(number);
+            return luaL_error(state, c"gpio: too many edge callbacks".as_ptr());
+        }
+
+        let top = lua_gettop(state);
+        check_stack(state, 2);
+        luaL_getsubtable(state, LUA_REGISTRYINDEX, EDGE_CALLBACKS.as_ptr());
+        lua_pushvalue(state, 3);
+        lua_rawseti(state, -2, number as _);
+        lua_settop(state, top);
+
+        lua_event::install_dispatch_hook(state);
+    }
+    0
+}
+
+/// `gpio.off_edge(pin)` stops watching `pin` and releases it.
+unsafe extern "C-unwind" fn gpio_off_edge(state: *mut c_void) -> c_int {
+    unsafe {
+        let number = luaL_checkinteger(state, 1);
+        let top = lua_gettop(state);
+        check_stack(state, 3);
+        luaL_getsubtable(state, LUA_REGISTRYINDEX, EDGE_CALLBACKS.as_ptr());
+        if (0..PIN_COUNT as _).contains(&number) && lua_rawgeti(state, -1, number) == LUA_TFUNCTION
+        {
+            EDGE_STOP[number as usize].signal(());
+            lua_pushnil(state);
+            lua_rawseti(state, -3, number);
+        }
+        lua_settop(state, top);
+    }
+    0
+}
+
+/// Call the callback registered for `pin`, if any. Errors are reported and
+/// do not propagate into the interrupted script.
+pub unsafe fn dispatch_edge(state: *mut c_void, pin: u8, high: bool) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        let top = lua_gettop(state);
+        check_stack(state, 4);
+        luaL_getsubtable(state, LUA_REGISTRYINDEX, EDGE_CALLBACKS.as_ptr());
+        if lua_rawgeti(state, -1, pin as _) == LUA_TFUNCTION {
+            lua_pushinteger(state, pin as _);
+            lua_pushinteger(state, high as _);
+            report(state, pcall_traceback(state, 2, 0));
+        }
+        lua_settop(state, top);
+        guard.check();
+    }
+}
+
+pub unsafe extern "C-unwind" fn luaopen_gpio(state: *mut c_void) -> c_int {
+    unsafe {
+        new_lib(
+            state,
+            &[
+                (c"output", gpio_output),
+                (c"input", gpio_input),
+                (c"on_edge", gpio_on_edge),
+                (c"off_edge", gpio_off_edge),
+            ],
+        );
+    }
+    1
+}
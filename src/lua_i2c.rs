@@ -0,0 +1,401 @@
+//! `i2c` Lua library.
+//!
+//! ```lua
+//! local bus = i2c.open(0, 4, 5, 400000)
+//! for _, addr in ipairs(bus:scan()) do print(addr) end
+//! bus:write_reg(0x76, 0xF4, 0x27)
+//! local id = bus:read_reg(0x76, 0xD0)
+//! local raw = bus:read_reg(0x76, 0xF7, 6)
+//! ```
+//!
+//! Data goes in and out as Lua strings. Every transfer runs under a timeout,
+//! and a NAK, lost arbitration or timeout raises a Lua error.
+
+use crate::lua::{
+    LUA_TNUMBER, LuaCFunction, LuaTable, LuaUserData, check_bytes, check_stack, check_userdata,
+    close_userdata, lua_pushinteger, lua_pushlstring, lua_type, luaL_checkinteger, luaL_error,
+    luaL_optinteger, new_lib, push_buffer, push_userdata,
+};
+use crate::pins;
+use core::cell::Cell;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_futures::block_on;
+use embassy_rp::Peri;
+use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::clk_peri_freq;
+use embassy_rp::i2c::{AbortReason, Async, Config, Error, I2c, InterruptHandler, SdaPin};
+use embassy_rp::peripherals::*;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, with_timeout};
+
+bind_interrupts!(struct Irqs {
+    I2C0_IRQ => InterruptHandler<I2C0>;
+    I2C1_IRQ => InterruptHandler<I2C1>;
+});
+
+const DEFAULT_FREQ: c_long = 100_000;
+const MAX_FREQ: c_long = 1_000_000;
+/// Longest a transfer may take, clock stretching included.
+const TIMEOUT: Duration = Duration::from_millis(100);
+/// Per address while scanning, so an empty bus does not take seconds.
+const SCAN_TIMEOUT: Duration = Duration::from_millis(10);
+/// Most bytes a single read will return.
+const MAX_READ: c_long = 1024;
+
+// one bit per controller in use
+static BUSES: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+enum Bus {
+    I2c0(I2c<'static, I2C0, Async>),
+    I2c1(I2c<'static, I2C1, Async>),
+}
+
+/// Evaluate `$body` with `$i2c` bound to whichever controller `$bus` holds.
+macro_rules! on_bus {
+    ($bus:expr, $i2c:ident => $body:expr) => {
+        match $bus {
+            Bus::I2c0($i2c) => $body,
+            Bus::I2c1($i2c) => $body,
+        }
+    };
+}
+
+macro_rules! with_scl {
+    ($name:ident, $peri:ident: $($pin:literal => $ty:ident),*) => {
+        /// Finish building the driver once the SDA pin is typed.
+        fn $name(
+            sda: Peri<'static, impl SdaPin<$peri>>,
+            scl: u8,
+            config: Config,
+        ) -> I2c<'static, $peri, Async> {
+            match scl {
+                // SAFETY: the pin came out of the registry, the controller out of BUSES
+                $($pin => unsafe {
+                    I2c::new_async($peri::steal(), $ty::steal(), sda, Irqs, config)
+                },)*
+                _ => core::unreachable!(),
+            }
+        }
+    };
+}
+
+with_scl!(i2c0_with_scl, I2C0: 1 => PIN_1, 5 => PIN_5, 9 => PIN_9, 13 => PIN_13,
+    17 => PIN_17, 21 => PIN_21, 25 => PIN_25, 29 => PIN_29);
+with_scl!(i2c1_with_scl, I2C1: 3 => PIN_3, 7 => PIN_7, 11 => PIN_11, 15 => PIN_15,
+    19 => PIN_19, 23 => PIN_23, 27 => PIN_27);
+
+/// Create the driver for a pair of pins already checked by [`pins_fit`].
+fn new_bus(sda: u8, scl: u8, config: Config) -> Bus {
+    // SAFETY: the pin came out of the registry
+    unsafe {
+        match sda {
+            0 => Bus::I2c0(i2c0_with_scl(PIN_0::steal(), scl, config)),
+            4 => Bus::I2c0(i2c0_with_scl(PIN_4::steal(), scl, config)),
+            8 => Bus::I2c0(i2c0_with_scl(PIN_8::steal(), scl, config)),
+            12 => Bus::I2c0(i2c0_with_scl(PIN_12::steal(), scl, config)),
+            16 => Bus::I2c0(i2c0_with_scl(PIN_16::steal(), scl, config)),
+            20 => Bus::I2c0(i2c0_with_scl(PIN_20::steal(), scl, config)),
+            24 => Bus::I2c0(i2c0_with_scl(PIN_24::steal(), scl, config)),
+            28 => Bus::I2c0(i2c0_with_scl(PIN_28::steal(), scl, config)),
+            2 => Bus::I2c1(i2c1_with_scl(PIN_2::steal(), scl, config)),
+            6 => Bus::I2c1(i2c1_with_scl(PIN_6::steal(), scl, config)),
+            10 => Bus::I2c1(i2c1_with_scl(PIN_10::steal(), scl, config)),
+            14 => Bus::I2c1(i2c1_with_scl(PIN_14::steal(), scl, config)),
+            18 => Bus::I2c1(i2c1_with_scl(PIN_18::steal(), scl, config)),
+            22 => Bus::I2c1(i2c1_with_scl(PIN_22::steal(), scl, config)),
+            26 => Bus::I2c1(i2c1_with_scl(PIN_26::steal(), scl, config)),
+            _ => core::unreachable!(),
+        }
+    }
+}
+
+/// Whether `sda` and `scl` are the SDA and SCL functions of controller
+/// `bus`. They repeat every four pins: SDA0, SCL0, SDA1, SCL1.
+fn pins_fit(bus: c_long, sda: c_long, scl: c_long) -> bool {
+    let fits = |pin: c_long, function| {
+        (0..pins::PIN_COUNT as c_long).contains(&pin) && pin % 4 == function
+    };
+    (0..=1).contains(&bus) && fits(sda, bus * 2) && fits(scl, bus * 2 + 1)
+}
+
+/// Why a transfer failed.
+enum Failure {
+    I2c(Error),
+    Timeout,
+}
+
+fn block(
+    timeout: Duration,
+    transfer: impl Future<Output = Result<(), Error>>,
+) -> Result<(), Failure> {
+    match block_on(with_timeout(timeout, transfer)) {
+        Ok(result) => result.map_err(Failure::I2c),
+        Err(_) => Err(Failure::Timeout),
+    }
+}
+
+/// An I2C controller and its two pins, claimed by a script.
+pub struct LuaI2c {
+    index: u8,
+    sda: u8,
+    scl: u8,
+    bus: Bus,
+}
+
+impl LuaI2c {
+    fn write(&mut self, addr: u8, bytes: impl IntoIterator<Item = u8>) -> Result<(), Failure> {
+        on_bus!(&mut self.bus, i2c => block(TIMEOUT, i2c.write_async(u16::from(addr), bytes)))
+    }
+
+    fn read(&mut self, addr: u8, buffer: &mut [u8], timeout: Duration) -> Result<(), Failure> {
+        on_bus!(&mut self.bus, i2c => block(timeout, i2c.read_async(u16::from(addr), buffer)))
+    }
+
+    fn write_read(
+        &mut self,
+        addr: u8,
+        bytes: impl IntoIterator<Item = u8>,
+        buffer: &mut [u8],
+    ) -> Result<(), Failure> {
+        on_bus!(&mut self.bus, i2c => {
+            block(TIMEOUT, i2c.write_read_async(u16::from(addr), bytes, buffer))
+        })
+    }
+}
+
+impl Drop for LuaI2c {
+    fn drop(&mut self) {
+        BUSES.lock(|buses| buses.set(buses.get() & !(1 << self.index)));
+        pins::release(self.sda);
+        pins::release(self.scl);
+    }
+}
+
+impl LuaUserData for LuaI2c {
+    const NAME: &'static CStr = c"i2c.Bus";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"write", bus_write),
+        (c"read", bus_read),
+        (c"write_read", bus_write_read),
+        (c"write_reg", bus_write_reg),
+        (c"read_reg", bus_read_reg),
+        (c"scan", bus_scan),
+        (c"close", bus_close),
+    ];
+}
+
+/// `i2c.open(bus, sda, scl [, freq])`
+unsafe extern "C-unwind" fn i2c_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let index = luaL_checkinteger(state, 1);
+        let sda = luaL_checkinteger(state, 2);
+        let scl = luaL_checkinteger(state, 3);
+        let freq = luaL_optinteger(state, 4, DEFAULT_FREQ);
+        if !pins_fit(index, sda, scl) {
+            return luaL_error(
+                state,
+                c"i2c %d: gpio %d/%d are not its SDA/SCL".as_ptr(),
+                index as c_int,
+                sda as c_int,
+                scl as c_int,
+            );
+        }
+        // the low count, 3/5 of a period in clk_peri cycles, has 16 bits
+        let min = clk_peri_freq().div_ceil(0xffff * 5 / 3) as c_long;
+        if !(min..=MAX_FREQ).contains(&freq) {
+            return luaL_error(
+                state,
+                c"i2c: frequency %d Hz out of range".as_ptr(),
+                freq as c_int,
+            );
+        }
+        let (index, sda, scl) = (index as u8, sda as u8, scl as u8);
+
+        let taken = BUSES.lock(|buses| {
+            let taken = buses.get() & (1 << index) != 0;
+            buses.set(buses.get() | (1 << index));
+            taken
+        });
+        if taken {
+            return luaL_error(state, c"i2c %d in use".as_ptr(), index as c_int);
+        }
+        let claimed =
+            pins::claim(sda).and_then(|_| pins::claim(scl).inspect_err(|_| pins::release(sda)));
+        if let Err(e) = claimed {
+            BUSES.lock(|buses| buses.set(buses.get() & !(1 << index)));
+            return luaL_error(
+                state,
+                c"i2c %d: %s".as_ptr(),
+                index as c_int,
+                e.message().as_ptr(),
+            );
+        }
+
+        let mut config = Config::default();
+        config.frequency = freq as u32;
+        push_userdata(
+            state,
+            LuaI2c {
+                index,
+                sda,
+                scl,
+                bus: new_bus(sda, scl, config),
+            },
+        );
+    }
+    1
+}
+
+/// A 7-bit address from argument `arg`.
+unsafe fn check_addr(state: *mut c_void, arg: c_int) -> u8 {
+    unsafe {
+        let addr = luaL_checkinteger(state, arg);
+        if !(0..=0x7f).contains(&addr) {
+            luaL_error(state, c"i2c: bad address %d".as_ptr(), addr as c_int);
+        }
+        addr as u8
+    }
+}
+
+/// Raise the Lua error for a failed transfer with device `addr`.
+unsafe fn check(state: *mut c_void, addr: u8, result: Result<(), Failure>) {
+    let fmt = match result {
+        Ok(()) => return,
+        Err(Failure::Timeout) => c"i2c: timeout talking to device %d",
+        Err(Failure::I2c(Error::Abort(AbortReason::NoAcknowledge))) => {
+            c"i2c: no acknowledge from device %d"
+        }
+        Err(Failure::I2c(Error::Abort(AbortReason::ArbitrationLoss))) => {
+            c"i2c: arbitration lost talking to device %d"
+        }
+        Err(_) => c"i2c: transfer with device %d failed",
+    };
+    unsafe { luaL_error(state, fmt.as_ptr(), addr as c_int) };
+}
+
+/// Push a buffer for reading `len` bytes into, to be pushed as the result
+/// string afterwards.
+unsafe fn read_buffer<'a>(state: *mut c_void, len: c_long) -> &'a mut [u8] {
+    unsafe {
+        if !(1..=MAX_READ).contains(&len) {
+            luaL_error(
+                state,
+                c"i2c: read must be 1..%d bytes".as_ptr(),
+                MAX_READ as c_int,
+            );
+        }
+        check_stack(state, 2);
+        push_buffer(state, len as usize)
+    }
+}
+
+/// `bus:write(addr, data)`
+unsafe extern "C-unwind" fn bus_write(state: *mut c_void) -> c_int {
+    unsafe {
+        let bus = check_userdata::<LuaI2c>(state, 1);
+        let addr = check_addr(state, 2);
+        let data = check_bytes(state, 3);
+        check(state, addr, bus.write(addr, data.iter().copied()));
+    }
+    0
+}
+
+/// `bus:read(addr, len)` returns `len` bytes as a string.
+unsafe extern "C-unwind" fn bus_read(state: *mut c_void) -> c_int {
+    unsafe {
+        let bus = check_userdata::<LuaI2c>(state, 1);
+        let addr = check_addr(state, 2);
+        let buffer = read_buffer(state, luaL_checkinteger(state, 3));
+        check(state, addr, bus.read(addr, buffer, TIMEOUT));
+        lua_pushlstring(state, buffer.as_ptr(), buffer.len());
+    }
+    1
+}
+
+/// `bus:write_read(addr, data, len)` writes `data`, then reads `len` bytes
+/// after a repeated start.
+unsafe extern "C-unwind" fn bus_write_read(state: *mut c_void) -> c_int {
+    unsafe {
+        let bus = check_userdata::<LuaI2c>(state, 1);
+        let addr = check_addr(state, 2);
+        let data = check_bytes(state, 3);
+        let buffer = read_buffer(state, luaL_checkinteger(state, 4));
+        check(
+            state,
+            addr,
+            bus.write_read(addr, data.iter().copied(), buffer),
+        );
+        lua_pushlstring(state, buffer.as_ptr(), buffer.len());
+    }
+    1
+}
+
+/// `bus:write_reg(addr, reg, value)` writes `reg` then `value`, a byte or a
+/// string of bytes.
+unsafe extern "C-unwind" fn bus_write_reg(state: *mut c_void) -> c_int {
+    unsafe {
+        let bus = check_userdata::<LuaI2c>(state, 1);
+        let addr = check_addr(state, 2);
+        let reg = luaL_checkinteger(state, 3) as u8;
+        let byte;
+        let value = if lua_type(state, 4) == LUA_TNUMBER {
+            byte = [luaL_checkinteger(state, 4) as u8];
+            &byte[..]
+        } else {
+            check_bytes(state, 4)
+        };
+        let bytes = core::iter::once(reg).chain(value.iter().copied());
+        check(state, addr, bus.write(addr, bytes));
+    }
+    0
+}
+
+/// `bus:read_reg(addr, reg [, len])` returns the byte at `reg`, or `len`
+/// bytes from `reg` on as a string.
+unsafe extern "C-unwind" fn bus_read_reg(state: *mut c_void) -> c_int {
+    unsafe {
+        let bus = check_userdata::<LuaI2c>(state, 1);
+        let addr = check_addr(state, 2);
+        let reg = luaL_checkinteger(state, 3) as u8;
+        let len = luaL_optinteger(state, 4, 0);
+        if len == 0 {
+            let mut byte = [0];
+            check(state, addr, bus.write_read(addr, [reg], &mut byte));
+            lua_pushinteger(state, c_long::from(byte[0]));
+        } else {
+            let buffer = read_buffer(state, len);
+            check(state, addr, bus.write_read(addr, [reg], buffer));
+            lua_pushlstring(state, buffer.as_ptr(), buffer.len());
+        }
+    }
+    1
+}
+
+/// `bus:scan()` returns the addresses that acknowledge a one-byte read.
+unsafe extern "C-unwind" fn bus_scan(state: *mut c_void) -> c_int {
+    unsafe {
+        let bus = check_userdata::<LuaI2c>(state, 1);
+        let found = LuaTable::new(state, 0, 0);
+        let mut count = 0;
+        // 0x00-0x07 and 0x78-0x7f are reserved
+        for addr in 0x08..0x78 {
+            if bus.read(addr, &mut [0], SCAN_TIMEOUT).is_ok() {
+                count += 1;
+                found.set(count as c_long, c_long::from(addr));
+            }
+        }
+    }
+    1
+}
+
+/// `bus:close()` releases the controller and its pins.
+unsafe extern "C-unwind" fn bus_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaI2c>(state, 1) };
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_i2c(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"open", i2c_open)]) };
+    1
+}
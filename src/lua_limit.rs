@@ -0,0 +1,164 @@
+//! Protected calls with an instruction and time budget.
+//!
+//! ```ignore
+//! let limits = Limits {
+//!     instructions: Some(100_000),
+//!     time: Some(Duration::from_millis(50)),
+//! };
+//! match pcall_limited(state, 0, 0, &limits) {
+//!     Ok(()) => {}
+//!     Err(CallError::Lua(status)) => report(state, status),
+//!     Err(exceeded) => { /* the script ran too long */ }
+//! }
+//! ```
+//!
+//! The budget is checked by a count hook every
+//! [`HOOK_INTERVAL`](crate::lua_event::HOOK_INTERVAL) instructions, so a
+//! native function that blocks is not cut short. Once a budget is spent the
+//! hook raises "budget exceeded" on every instruction, which a `pcall` in
+//! the script cannot get past.
+
+use crate::lua::{
+    LUA_MASKCOUNT, LUA_OK, LUA_REGISTRYINDEX, LuaHook, check_stack, lua_getfield, lua_gethook,
+    lua_gethookcount, lua_gethookmask, lua_pop, lua_pushlightuserdata, lua_setfield, lua_sethook,
+    lua_touserdata, luaL_error, pcall_traceback,
+};
+use crate::lua_event::HOOK_INTERVAL;
+use core::cell::Cell;
+use core::ffi::{CStr, c_int, c_void};
+use embassy_time::{Duration, Instant};
+
+/// Registry field holding the innermost limited call's [`Limit`].
+const CURRENT: &CStr = c"limit.current";
+
+/// What a [`pcall_limited`] may use. A `None` is no limit.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    /// Most VM instructions the call may run, counted in steps of
+    /// [`HOOK_INTERVAL`].
+    pub instructions: Option<u32>,
+    /// Longest the call may take.
+    pub time: Option<Duration>,
+}
+
+/// Why a [`pcall_limited`] failed. The error message is left on the stack
+/// either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CallError {
+    /// The call raised an error, with this status.
+    Lua(c_int),
+    /// The call ran out of instructions.
+    Instructions,
+    /// The call ran past its time.
+    Time,
+}
+
+/// A running limited call, reached by the hook through the registry.
+struct Limit {
+    instructions: Cell<Option<u32>>,
+    deadline: Option<Instant>,
+    exceeded: Cell<Option<CallError>>,
+    /// The hook the call replaced, run by this one.
+    previous: Option<LuaHook>,
+    /// The limited call this one is nested in, or null.
+    outer: *const Limit,
+}
+
+impl Limit {
+    /// Charge one hook interval. Returns whether the budget is spent.
+    fn charge(&self) -> bool {
+        if self.exceeded.get().is_none() {
+            match self.instructions.get() {
+                Some(left) if left <= HOOK_INTERVAL as u32 => {
+                    self.exceeded.set(Some(CallError::Instructions))
+                }
+                Some(left) => self.instructions.set(Some(left - HOOK_INTERVAL as u32)),
+                None => {}
+            }
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.exceeded.set(Some(CallError::Time));
+            }
+        }
+        self.exceeded.get().is_some()
+    }
+}
+
+/// The innermost limited call running in `state`, or null.
+unsafe fn current(state: *mut c_void) -> *const Limit {
+    unsafe {
+        check_stack(state, 1);
+        lua_getfield(state, LUA_REGISTRYINDEX, CURRENT.as_ptr());
+        let limit = lua_touserdata(state, -1) as *const Limit;
+        lua_pop(state, 1);
+        limit
+    }
+}
+
+unsafe extern "C-unwind" fn limit_hook(state: *mut c_void, ar: *mut c_void) {
+    unsafe {
+        // a thread created in a limited call keeps the hook after it
+        let mut limit = current(state);
+        let mut exceeded = false;
+        let mut previous = None;
+        while let Some(l) = limit.as_ref() {
+            exceeded |= l.charge();
+            previous = l.previous;
+            limit = l.outer;
+        }
+        if exceeded {
+            // from now on stop at every instruction, so that the error gets
+            // out of any pcall in the script
+            lua_sethook(state, Some(limit_hook), LUA_MASKCOUNT, 1);
+            luaL_error(state, c"budget exceeded".as_ptr());
+            return;
+        }
+        if let Some(previous) = previous {
+            previous(state, ar);
+        }
+    }
+}
+
+/// [`pcall_traceback`] the function below `nargs` arguments, stopping it
+/// with an error if it runs past `limits`. Like a task's calls, it must not
+/// yield.
+pub unsafe fn pcall_limited(
+    state: *mut c_void,
+    nargs: c_int,
+    nresults: c_int,
+    limits: &Limits,
+) -> Result<(), CallError> {
+    unsafe {
+        let previous = (
+            lua_gethook(state),
+            lua_gethookmask(state),
+            lua_gethookcount(state),
+        );
+        let outer = current(state);
+        let limit = Limit {
+            instructions: Cell::new(limits.instructions),
+            deadline: limits.time.map(|time| Instant::now() + time),
+            exceeded: Cell::new(None),
+            // nested, the hook being replaced is this one; the outermost
+            // call has the one to run
+            previous: if outer.is_null() { previous.0 } else { None },
+            outer,
+        };
+        check_stack(state, 1);
+        lua_pushlightuserdata(state, &limit as *const Limit as *mut c_void);
+        lua_setfield(state, LUA_REGISTRYINDEX, CURRENT.as_ptr());
+        lua_sethook(state, Some(limit_hook), LUA_MASKCOUNT, HOOK_INTERVAL);
+
+        let status = pcall_traceback(state, nargs, nresults);
+
+        lua_sethook(state, previous.0, previous.1, previous.2);
+        lua_pushlightuserdata(state, limit.outer as *mut c_void);
+        lua_setfield(state, LUA_REGISTRYINDEX, CURRENT.as_ptr());
+        if status == LUA_OK {
+            return Ok(());
+        }
+        Err(limit.exceeded.get().unwrap_or(CallError::Lua(status)))
+    }
+}
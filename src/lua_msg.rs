@@ -0,0 +1,292 @@
+//! `msg` Lua library: messages between [`LuaState`](crate::lua_state::LuaState)s.
+//!
+//! ```lua
+//! msg.send(1, { cmd = "blink", period = 250 })
+//! local m, from = msg.receive(1000)
+//! if m then print(m.cmd, "from", from) end
+//! print(msg.id())
+//! ```
+//!
+//! A message is a copy of one value: `nil`, a boolean, a number, a string, or
+//! a table of those, serialized by the sender and rebuilt by the receiver.
+//! Each state has a mailbox of [`MAILBOX`] messages. `receive` in a task
+//! waits without holding up the others.
+
+use crate::lua::{
+    LUA_TBOOLEAN, LUA_TNIL, LUA_TNUMBER, LUA_TSTRING, LUA_TTABLE, StackGuard, check_stack,
+    lua_absindex, lua_createtable, lua_isinteger, lua_next, lua_pop, lua_pushboolean,
+    lua_pushinteger, lua_pushlstring, lua_pushnil, lua_pushnumber, lua_rawset, lua_toboolean,
+    lua_tointegerx, lua_tolstring, lua_tonumberx, lua_type, lua_typename, luaL_checkany,
+    luaL_checkinteger, luaL_error, luaL_optinteger, new_lib, push_buffer,
+};
+use crate::lua_async::await_future;
+use crate::lua_state::{self, MAX_STATES};
+use core::ffi::{c_int, c_long, c_void};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, with_deadline};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Messages a state can have waiting.
+pub const MAILBOX: usize = 4;
+/// Largest serialized message.
+const MAX_MESSAGE: usize = 1024;
+/// Deepest tables can nest in a message, which also keeps out cycles.
+const MAX_DEPTH: usize = 8;
+
+// value tags
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const FLOAT: u8 = 4;
+const STRING: u8 = 5;
+const TABLE: u8 = 6;
+/// Ends the key/value pairs of a table.
+const END: u8 = 7;
+
+struct Message {
+    from: u8,
+    data: Vec<u8>,
+}
+
+static MAILBOXES: [Channel<CriticalSectionRawMutex, Message, MAILBOX>; MAX_STATES] =
+    [const { Channel::new() }; MAX_STATES];
+
+/// Drop the messages waiting for state `id`, e.g. when it closes.
+pub fn clear(id: u8) {
+    while MAILBOXES[id as usize].try_receive().is_ok() {}
+}
+
+enum EncodeError {
+    /// A value of this Lua type cannot be sent.
+    Type(c_int),
+    TooDeep,
+    TooLarge,
+}
+
+/// Append the value at `idx` to `out`. The stack must have room for a key
+/// and value per level of nesting.
+unsafe fn encode(
+    state: *mut c_void,
+    idx: c_int,
+    depth: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), EncodeError> {
+    unsafe {
+        match lua_type(state, idx) {
+            LUA_TNIL => out.push(NIL),
+            LUA_TBOOLEAN if lua_toboolean(state, idx) != 0 => out.push(TRUE),
+            LUA_TBOOLEAN => out.push(FALSE),
+            LUA_TNUMBER if lua_isinteger(state, idx) != 0 => {
+                out.push(INTEGER);
+                let n = lua_tointegerx(state, idx, core::ptr::null_mut());
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            LUA_TNUMBER => {
+                out.push(FLOAT);
+                let n = lua_tonumberx(state, idx, core::ptr::null_mut());
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            LUA_TSTRING => {
+                let mut len = 0;
+                let s = lua_tolstring(state, idx, &mut len);
+                // check before copying, not after, so a long string is
+                // never copied just to be thrown away
+                if out.len() + 5 + len as usize > MAX_MESSAGE {
+                    return Err(EncodeError::TooLarge);
+                }
+                let bytes = core::slice::from_raw_parts(s, len as usize);
+                out.push(STRING);
+                out.extend_from_slice(&(len as u32).to_le_bytes());
+                out.extend_from_slice(bytes);
+            }
+            LUA_TTABLE => {
+                if depth == MAX_DEPTH {
+                    return Err(EncodeError::TooDeep);
+                }
+                // room for the markers at least
+                if out.len() + 2 > MAX_MESSAGE {
+                    return Err(EncodeError::TooLarge);
+                }
+                let guard = StackGuard::new(state);
+                let idx = lua_absindex(state, idx);
+                out.push(TABLE);
+                lua_pushnil(state);
+                while lua_next(state, idx) != 0 {
+                    encode(state, -2, depth + 1, out)?;
+                    encode(state, -1, depth + 1, out)?;
+                    lua_pop(state, 1);
+                }
+                guard.check();
+                out.push(END);
+            }
+            other => return Err(EncodeError::Type(other)),
+        }
+    }
+    if out.len() > MAX_MESSAGE {
+        return Err(EncodeError::TooLarge);
+    }
+    Ok(())
+}
+
+/// Take `n` bytes off the front of `data`.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (head, tail) = data.split_at_checked(n)?;
+    *data = tail;
+    Some(head)
+}
+
+/// Push the value at the front of `data`, taking it off. Returns `None` if
+/// the data is not a message.
+unsafe fn decode(state: *mut c_void, data: &mut &[u8]) -> Option<()> {
+    unsafe {
+        check_stack(state, 3);
+        match take(data, 1)?[0] {
+            NIL => lua_pushnil(state),
+            FALSE => lua_pushboolean(state, 0),
+            TRUE => lua_pushboolean(state, 1),
+            INTEGER => {
+                let bytes = take(data, size_of::<c_long>())?;
+                lua_pushinteger(state, c_long::from_le_bytes(bytes.try_into().ok()?));
+            }
+            FLOAT => {
+                let bytes = take(data, size_of::<f64>())?;
+                lua_pushnumber(state, f64::from_le_bytes(bytes.try_into().ok()?));
+            }
+            STRING => {
+                let len = u32::from_le_bytes(take(data, 4)?.try_into().ok()?);
+                let bytes = take(data, len as usize)?;
+                lua_pushlstring(state, bytes.as_ptr(), bytes.len());
+            }
+            TABLE => {
+                lua_createtable(state, 0, 0);
+                while *data.first()? != END {
+                    decode(state, data)?;
+                    decode(state, data)?;
+                    lua_rawset(state, -3);
+                }
+                take(data, 1)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+/// Await a message for state `id` until `deadline`.
+async fn receive(id: u8, deadline: Instant) -> Option<Message> {
+    with_deadline(deadline, MAILBOXES[id as usize].receive())
+        .await
+        .ok()
+}
+
+/// The id of the state `state` belongs to.
+unsafe fn own_id(state: *mut c_void) -> u8 {
+    match unsafe { lua_state::id(state) } {
+        Some(id) => id,
+        None => {
+            unsafe { luaL_error(state, c"msg: not a LuaState".as_ptr()) };
+            core::unreachable!()
+        }
+    }
+}
+
+/// `msg.send(to, value)` sends a copy of `value` to the state with id `to`.
+/// Returns `false` if that state is not open or its mailbox is full.
+unsafe extern "C-unwind" fn msg_send(state: *mut c_void) -> c_int {
+    unsafe {
+        let from = own_id(state);
+        let to = luaL_checkinteger(state, 1);
+        if !(0..MAX_STATES as c_long).contains(&to) {
+            return luaL_error(state, c"msg: no state %d".as_ptr(), to as c_int);
+        }
+        luaL_checkany(state, 2);
+        // room for encode to walk the deepest tables, so it cannot raise
+        // an error with the message half built
+        check_stack(state, 2 * MAX_DEPTH as c_int + 1);
+        let mut data = Vec::new();
+        let encoded = encode(state, 2, 0, &mut data);
+        // nothing allocated may be left behind when raising an error
+        let sent = match encoded {
+            Ok(()) => {
+                lua_state::is_open(to as u8)
+                    && MAILBOXES[to as usize]
+                        .try_send(Message { from, data })
+                        .is_ok()
+            }
+            Err(e) => {
+                drop(data);
+                return match e {
+                    EncodeError::Type(t) => luaL_error(
+                        state,
+                        c"msg: cannot send a %s".as_ptr(),
+                        lua_typename(state, t),
+                    ),
+                    EncodeError::TooDeep => {
+                        luaL_error(state, c"msg: tables nested too deep".as_ptr())
+                    }
+                    EncodeError::TooLarge => luaL_error(
+                        state,
+                        c"msg: message over %d bytes".as_ptr(),
+                        MAX_MESSAGE as c_int,
+                    ),
+                };
+            }
+        };
+        lua_pushboolean(state, sent as c_int);
+    }
+    1
+}
+
+/// `msg.receive([timeout_ms])` returns the next message and the id of the
+/// state that sent it, or `nil` if none arrived in time. The default
+/// timeout of 0 takes only a message that is already waiting.
+unsafe extern "C-unwind" fn msg_receive(state: *mut c_void) -> c_int {
+    unsafe {
+        let id = own_id(state);
+        let timeout = luaL_optinteger(state, 1, 0).max(0);
+        let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+        await_future(state, receive(id, deadline), |state, message| {
+            let Some(message) = message else {
+                lua_pushnil(state);
+                return 1;
+            };
+            // decoding may run out of memory
+            let data = push_buffer(state, message.data.len());
+            data.copy_from_slice(&message.data);
+            let from = message.from;
+            drop(message);
+            if decode(state, &mut &data[..]).is_none() {
+                return luaL_error(state, c"msg: bad message".as_ptr());
+            }
+            lua_pushinteger(state, c_long::from(from));
+            2
+        })
+    }
+}
+
+/// `msg.id()` returns the id of this state.
+unsafe extern "C-unwind" fn msg_id(state: *mut c_void) -> c_int {
+    unsafe {
+        let id = own_id(state);
+        lua_pushinteger(state, c_long::from(id));
+    }
+    1
+}
+
+pub unsafe extern "C-unwind" fn luaopen_msg(state: *mut c_void) -> c_int {
+    unsafe {
+        new_lib(
+            state,
+            &[
+                (c"send", msg_send),
+                (c"receive", msg_receive),
+                (c"id", msg_id),
+            ],
+        );
+    }
+    1
+}
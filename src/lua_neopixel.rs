@@ -0,0 +1,298 @@
+//! `neopixel` Lua library.
+//!
+//! ```lua
+//! local strip = neopixel.open(16, { brightness = 0.25 })
+//! strip:write({ 0xff0000, 0x00ff00, 0x0000ff })
+//! strip:write(string.rep("\255\128\0", 8))
+//! strip:fill(0x000000, 60)
+//! ```
+//!
+//! WS2812 LEDs on one GPIO, clocked out by a state machine taken from the
+//! [`pio`](crate::lua_pio) blocks. A table holds one `0xRRGGBB` integer per
+//! LED, a string three bytes (red, green, blue) per LED; either way the
+//! pixels go out by DMA on the channel handed to [`init`].
+
+use crate::lua::{
+    LuaCFunction, LuaTable, LuaUserData, check_bytes, check_userdata, close_userdata,
+    lua_pushnumber, lua_type, luaL_checkinteger, luaL_checknumber, luaL_error, new_lib,
+    push_buffer, push_userdata,
+};
+use crate::lua_pio::{LuaPioSm, OpenError, Pins, Settings};
+use crate::pins;
+use core::cell::RefCell;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_futures::block_on;
+use embassy_rp::Peri;
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::peripherals::DMA_CH7;
+use embassy_rp::pio::FifoJoin;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use fixed::types::U24F8;
+use pio::{
+    Assembler, JmpCondition, OutDestination, Program, RP2040_MAX_PROGRAM_SIZE, SetDestination,
+    SideSet,
+};
+
+extern crate alloc;
+use alloc::string::String;
+
+/// Most LEDs a single write will drive.
+const MAX_PIXELS: usize = 1024;
+/// The LEDs read bits at 800 kHz.
+const BIT_RATE: u32 = 800_000;
+/// PIO cycles spent on the start, data and stop parts of each bit.
+const T1: u8 = 2;
+const T2: u8 = 5;
+const T3: u8 = 3;
+/// Time for one LED's 24 bits.
+const PIXEL_TIME: Duration = Duration::from_micros(30);
+/// Pixels still in the TX FIFO, 8 deep as it is joined, and the output
+/// shift register when the DMA transfer ends.
+const QUEUED: u32 = 9;
+/// How long the line must stay low for the LEDs to latch what they were
+/// sent; WS2812B parts need 280 us.
+const RESET_TIME: Duration = Duration::from_micros(300);
+
+static DMA: Mutex<CriticalSectionRawMutex, RefCell<Option<Peri<'static, DMA_CH7>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Give the library the DMA channel for writes.
+pub fn init(dma: Peri<'static, DMA_CH7>) {
+    DMA.lock(|cell| cell.replace(Some(dma)));
+}
+
+/// Each bit is a high start, then high or low data, then a low stop. Bits
+/// come out of the shift register MSB first, 24 per LED.
+fn program() -> Program<RP2040_MAX_PROGRAM_SIZE> {
+    let mut a = Assembler::new_with_side_set(SideSet::new(false, 1, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut do_zero = a.label();
+    a.set_with_side_set(SetDestination::PINDIRS, 1, 0);
+    a.bind(&mut wrap_target);
+    a.out_with_delay_and_side_set(OutDestination::X, 1, T3 - 1, 0);
+    a.jmp_with_delay_and_side_set(JmpCondition::XIsZero, &mut do_zero, T1 - 1, 1);
+    a.jmp_with_delay_and_side_set(JmpCondition::Always, &mut wrap_target, T2 - 1, 1);
+    a.bind(&mut do_zero);
+    a.nop_with_delay_and_side_set(T2 - 1, 0);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+fn settings(pin: u8) -> Settings {
+    let cycles_per_bit = u32::from(T1 + T2 + T3);
+    let divider = f64::from(clk_sys_freq()) / f64::from(BIT_RATE * cycles_per_bit);
+    Settings {
+        out: None,
+        set: None,
+        sideset: Some(Pins {
+            base: pin,
+            count: 1,
+        }),
+        in_base: None,
+        jmp: None,
+        divider: U24F8::from_num(divider),
+        shift_out: (false, Some(24)),
+        shift_in: (true, None),
+        join: FifoJoin::TxOnly,
+    }
+}
+
+/// A strip of LEDs opened by a script.
+pub struct LuaNeopixel {
+    sm: LuaPioSm,
+    /// Where red, green and blue go in the word for one LED.
+    shifts: [u8; 3],
+    /// Brightness out of 256.
+    level: u32,
+    /// When the LEDs will have latched the last write.
+    ready: Instant,
+}
+
+impl LuaUserData for LuaNeopixel {
+    const NAME: &'static CStr = c"neopixel.Strip";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"write", strip_write),
+        (c"fill", strip_fill),
+        (c"brightness", strip_brightness),
+        (c"close", strip_close),
+    ];
+}
+
+/// The FIFO word for a `0xRRGGBB` colour, with red, green and blue moved
+/// to `shifts` and scaled by `level` out of 256.
+pub(crate) fn word(shifts: [u8; 3], level: u32, rgb: u32) -> u32 {
+    let mut word = 0;
+    for (i, shift) in shifts.iter().enumerate() {
+        let value = (rgb >> (16 - 8 * i)) & 0xff;
+        word |= ((value * level) >> 8) << shift;
+    }
+    word
+}
+
+/// Shifts for an `order` like `"grb"`, which must name each colour once.
+pub(crate) fn shifts(order: &str) -> Option<[u8; 3]> {
+    if order.len() != 3 {
+        return None;
+    }
+    let mut shifts = [0; 3];
+    for (shift, colour) in shifts.iter_mut().zip(['r', 'g', 'b']) {
+        *shift = 24 - 8 * order.find(colour)? as u8;
+    }
+    Some(shifts)
+}
+
+/// Brightness from 0 to 1, as a level out of 256.
+unsafe fn check_level(state: *mut c_void, brightness: f64) -> u32 {
+    if !(0.0..=1.0).contains(&brightness) {
+        unsafe { luaL_error(state, c"neopixel: brightness must be 0..1".as_ptr()) };
+    }
+    (brightness * 256.0 + 0.5) as u32
+}
+
+/// `neopixel.open(pin [, {order = "grb", brightness = 1}])`
+///
+/// `order` is the order the LEDs take red, green and blue in.
+unsafe extern "C-unwind" fn neopixel_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let pin = luaL_checkinteger(state, 1);
+        if !(0..pins::PIN_COUNT as c_long).contains(&pin) {
+            return luaL_error(state, c"neopixel: no gpio %d".as_ptr(), pin as c_int);
+        }
+        let options = LuaTable::from_stack(state, 2);
+        let shifts = match options.as_ref().and_then(|o| o.get::<_, String>("order")) {
+            Some(order) => shifts(&order),
+            None => shifts("grb"),
+        };
+        let Some(shifts) = shifts else {
+            return luaL_error(state, c"neopixel: order must be like \"grb\"".as_ptr());
+        };
+        let brightness = options
+            .as_ref()
+            .and_then(|options| options.get::<_, f64>("brightness"))
+            .unwrap_or(1.0);
+        let level = check_level(state, brightness);
+
+        let mut sm = match LuaPioSm::open_any(&program(), &settings(pin as u8)) {
+            Ok(sm) => sm,
+            Err(OpenError::Pin(pin, e)) => {
+                return luaL_error(
+                    state,
+                    c"neopixel: gpio %d: %s".as_ptr(),
+                    pin as c_int,
+                    e.message().as_ptr(),
+                );
+            }
+            Err(OpenError::Pio(message)) => return luaL_error(state, message.as_ptr()),
+        };
+        sm.set_enable(true);
+        push_userdata(
+            state,
+            LuaNeopixel {
+                sm,
+                shifts,
+                level,
+                ready: Instant::now(),
+            },
+        );
+    }
+    1
+}
+
+/// Send `count` LEDs, colour `rgb(i)` for the `i`th, to the strip at
+/// argument 1.
+unsafe fn show(state: *mut c_void, count: usize, rgb: impl Fn(usize) -> u32) {
+    unsafe {
+        let strip = check_userdata::<LuaNeopixel>(state, 1);
+        if count > MAX_PIXELS {
+            luaL_error(
+                state,
+                c"neopixel: at most %d pixels".as_ptr(),
+                MAX_PIXELS as c_int,
+            );
+        }
+        // `rgb` may raise an error
+        let words = push_buffer::<u32>(state, count);
+        for (i, slot) in words.iter_mut().enumerate() {
+            *slot = word(strip.shifts, strip.level, rgb(i));
+        }
+
+        block_on(Timer::at(strip.ready));
+        let Some(mut dma) = DMA.lock(|cell| cell.take()) else {
+            luaL_error(state, c"neopixel: DMA not available".as_ptr());
+            core::unreachable!()
+        };
+        strip.sm.dma_push(dma.reborrow(), words);
+        DMA.lock(|cell| cell.replace(Some(dma)));
+        strip.ready = Instant::now() + PIXEL_TIME * QUEUED + RESET_TIME;
+    }
+}
+
+/// `strip:write(pixels)` sends a table of `0xRRGGBB` colours or a string of
+/// red, green, blue bytes, starting from the first LED.
+unsafe extern "C-unwind" fn strip_write(state: *mut c_void) -> c_int {
+    unsafe {
+        check_userdata::<LuaNeopixel>(state, 1);
+        if let Some(table) = LuaTable::from_stack(state, 2) {
+            show(state, table.len(), |i| {
+                match table.get::<_, c_long>(i as c_long + 1) {
+                    Some(rgb) => rgb as u32,
+                    None => {
+                        luaL_error(
+                            state,
+                            c"neopixel: bad colour at %d".as_ptr(),
+                            i as c_int + 1,
+                        );
+                        core::unreachable!()
+                    }
+                }
+            });
+        } else {
+            let bytes = check_bytes(state, 2);
+            if bytes.len() % 3 != 0 {
+                return luaL_error(state, c"neopixel: string must be 3 bytes a pixel".as_ptr());
+            }
+            show(state, bytes.len() / 3, |i| {
+                let [r, g, b] = [bytes[3 * i], bytes[3 * i + 1], bytes[3 * i + 2]];
+                u32::from_be_bytes([0, r, g, b])
+            });
+        }
+    }
+    0
+}
+
+/// `strip:fill(colour, count)` sets the first `count` LEDs to `0xRRGGBB`.
+unsafe extern "C-unwind" fn strip_fill(state: *mut c_void) -> c_int {
+    unsafe {
+        let rgb = luaL_checkinteger(state, 2) as u32;
+        let count = luaL_checkinteger(state, 3).max(0);
+        show(state, count as usize, |_| rgb);
+    }
+    0
+}
+
+/// `strip:brightness([level])` sets the brightness, 0 to 1, for later
+/// writes. Returns the brightness.
+unsafe extern "C-unwind" fn strip_brightness(state: *mut c_void) -> c_int {
+    unsafe {
+        let strip = check_userdata::<LuaNeopixel>(state, 1);
+        if lua_type(state, 2) > 0 {
+            strip.level = check_level(state, luaL_checknumber(state, 2));
+        }
+        lua_pushnumber(state, f64::from(strip.level) / 256.0);
+    }
+    1
+}
+
+/// `strip:close()` stops the state machine and releases the pin.
+unsafe extern "C-unwind" fn strip_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaNeopixel>(state, 1) };
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_neopixel(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"open", neopixel_open)]) };
+    1
+}
@@ -0,0 +1,145 @@
+//! `os` Lua library: Lua's own `loslib.c` on newlib, with the parts a board
+//! has no use for replaced.
+//!
+//! ```lua
+//! os.settime(1700000000)
+//! print(os.date("%Y-%m-%d %H:%M:%S"), os.time(), os.clock())
+//! print(os.getenv("BOARD"))
+//! os.exit()
+//! ```
+//!
+//! `os.time` and `os.date` read a clock kept on embassy-time through the
+//! `_gettimeofday` syscall. It counts from the Unix epoch at boot until
+//! `os.settime` or [`set_time`] sets it. The RP2040's RTC would keep time
+//! no longer: `embassy_rp::init` resets it at every boot, and it counts
+//! only whole seconds, in calendar fields. `os.clock` is the seconds since
+//! boot, `os.getenv` looks in the store [`set_env`] fills, and `os.exit`
+//! resets the board.
+//!
+//! Still to do: the firmware has no flash filesystem, for `io.open` either.
+//! Until it does, `os.remove` and `os.rename` return `nil`,
+//! `"<name>: no filesystem"` and `ENOSYS`.
+
+use crate::lua::{
+    LuaCFunction, check_bytes, check_stack, lua_pushcclosure, lua_pushfstring, lua_pushinteger,
+    lua_pushlstring, lua_pushnil, lua_pushnumber, lua_setfield, luaL_checkinteger,
+    luaL_checklstring,
+};
+use crate::lua_sys;
+use core::cell::{Cell, RefCell};
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+unsafe extern "C-unwind" {
+    #[link_name = "luaopen_os"]
+    unsafe fn luaopen_loslib(state: *mut c_void) -> c_int;
+}
+
+/// Newlib's errno for a function not implemented.
+const ENOSYS: c_long = 88;
+
+// Unix time at boot, in microseconds
+static BOOT_TIME: Mutex<CriticalSectionRawMutex, Cell<i64>> = Mutex::new(Cell::new(0));
+
+static ENV: Mutex<CriticalSectionRawMutex, RefCell<Vec<(&'static str, &'static str)>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Microseconds since the Unix epoch.
+pub fn unix_micros() -> i64 {
+    BOOT_TIME.lock(|cell| cell.get()) + Instant::now().as_micros() as i64
+}
+
+/// Set the clock to `secs` seconds since the Unix epoch.
+pub fn set_time(secs: i64) {
+    let boot = secs * 1_000_000 - Instant::now().as_micros() as i64;
+    BOOT_TIME.lock(|cell| cell.set(boot));
+}
+
+/// Make `os.getenv(name)` return `value`.
+pub fn set_env(name: &'static str, value: &'static str) {
+    ENV.lock(|env| {
+        let mut env = env.borrow_mut();
+        match env.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = value,
+            None => env.push((name, value)),
+        }
+    });
+}
+
+/// `os.clock()` returns the seconds since boot.
+unsafe extern "C-unwind" fn os_clock(state: *mut c_void) -> c_int {
+    let micros = Instant::now().as_micros();
+    unsafe { lua_pushnumber(state, micros as f64 / 1e6) };
+    1
+}
+
+/// `os.settime(t)` sets the clock `os.time` and `os.date` read to `t`
+/// seconds since the Unix epoch.
+unsafe extern "C-unwind" fn os_settime(state: *mut c_void) -> c_int {
+    let secs = unsafe { luaL_checkinteger(state, 1) };
+    set_time(secs as i64);
+    0
+}
+
+/// `os.getenv(name)` returns the value set for `name`, or `nil`.
+unsafe extern "C-unwind" fn os_getenv(state: *mut c_void) -> c_int {
+    let name = unsafe { check_bytes(state, 1) };
+    let value = ENV.lock(|env| {
+        env.borrow()
+            .iter()
+            .find(|(n, _)| n.as_bytes() == name)
+            .map(|(_, value)| *value)
+    });
+    unsafe {
+        match value {
+            Some(value) => {
+                lua_pushlstring(state, value.as_ptr(), value.len());
+            }
+            None => lua_pushnil(state),
+        }
+    }
+    1
+}
+
+/// `os.remove(name)` and `os.rename(name, new)` fail as on a system without
+/// a filesystem.
+unsafe extern "C-unwind" fn os_no_filesystem(state: *mut c_void) -> c_int {
+    unsafe {
+        let name = luaL_checklstring(state, 1, core::ptr::null_mut());
+        check_stack(state, 3);
+        lua_pushnil(state);
+        lua_pushfstring(state, c"%s: no filesystem".as_ptr(), name);
+        lua_pushinteger(state, ENOSYS);
+    }
+    3
+}
+
+/// `os.exit()` resets the board; there is no shell to return to.
+unsafe extern "C-unwind" fn os_exit(_state: *mut c_void) -> c_int {
+    lua_sys::reset()
+}
+
+pub unsafe extern "C-unwind" fn luaopen_os(state: *mut c_void) -> c_int {
+    unsafe {
+        luaopen_loslib(state);
+        check_stack(state, 1);
+        let funcs: [(&CStr, LuaCFunction); 6] = [
+            (c"clock", os_clock),
+            (c"settime", os_settime),
+            (c"getenv", os_getenv),
+            (c"remove", os_no_filesystem),
+            (c"rename", os_no_filesystem),
+            (c"exit", os_exit),
+        ];
+        for (name, func) in funcs {
+            lua_pushcclosure(state, func, 0);
+            lua_setfield(state, -2, name.as_ptr());
+        }
+    }
+    1
+}
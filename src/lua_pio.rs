@@ -0,0 +1,737 @@
+//! `pio` Lua library.
+//!
+//! ```lua
+//! -- set pins, 1 [31]
+//! -- set pins, 0 [31]
+//! local blink = pio.open(0, 0, { 0xff01, 0xff00 }, { set = 15, freq = 2000 })
+//! blink:enable()
+//!
+//! local tx = pio.open(0, 1, { 0x6001, sideset_bits = 1 }, { out = 16, sideset = 17 })
+//! tx:put(0x55, 100)
+//! ```
+//!
+//! A program is an array of assembled instructions, as printed by `pioasm`,
+//! with optional `wrap_target`, `wrap`, `origin`, `sideset_bits`,
+//! `sideset_opt` and `sideset_pindirs` fields. It is loaded into the
+//! instruction memory of the block handed to [`init`] and freed again when
+//! the state machine is closed.
+
+use crate::lua::{
+    LuaCFunction, LuaTable, LuaUserData, check_userdata, close_userdata, lua_pushboolean,
+    lua_pushinteger, lua_pushnil, lua_toboolean, lua_type, luaL_checkinteger, luaL_error,
+    luaL_optinteger, new_lib, push_userdata,
+};
+use crate::pins;
+use arrayvec::ArrayVec;
+use core::cell::RefCell;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_futures::block_on;
+use embassy_rp::Peri;
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::dma::Channel;
+use embassy_rp::peripherals::*;
+use embassy_rp::pio::{
+    Common, Config, Direction, FifoJoin, Instance, InstanceMemory, Pin, Pio, ShiftConfig,
+    ShiftDirection, StateMachine,
+};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, with_deadline};
+use fixed::types::U24F8;
+use pio::{PioVersion, Program, RP2040_MAX_PROGRAM_SIZE, SideSet, Wrap};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// What scripts may use of one PIO block: its instruction memory and the
+/// state machines not currently opened.
+struct Block<P: Instance + 'static> {
+    common: Common<'static, P>,
+    sm0: Option<StateMachine<'static, P, 0>>,
+    sm1: Option<StateMachine<'static, P, 1>>,
+    sm2: Option<StateMachine<'static, P, 2>>,
+    sm3: Option<StateMachine<'static, P, 3>>,
+}
+
+type BlockCell<P> = Mutex<CriticalSectionRawMutex, RefCell<Option<Block<P>>>>;
+
+static PIO0_BLOCK: BlockCell<PIO0> = Mutex::new(RefCell::new(None));
+static PIO1_BLOCK: BlockCell<PIO1> = Mutex::new(RefCell::new(None));
+
+/// Maps a PIO peripheral to where its [`Block`] is kept.
+trait HasBlock: Instance {
+    fn block() -> &'static BlockCell<Self>;
+}
+
+impl HasBlock for PIO0 {
+    fn block() -> &'static BlockCell<Self> {
+        &PIO0_BLOCK
+    }
+}
+
+impl HasBlock for PIO1 {
+    fn block() -> &'static BlockCell<Self> {
+        &PIO1_BLOCK
+    }
+}
+
+impl<P: Instance> From<Pio<'static, P>> for Block<P> {
+    fn from(pio: Pio<'static, P>) -> Self {
+        Self {
+            common: pio.common,
+            sm0: Some(pio.sm0),
+            sm1: Some(pio.sm1),
+            sm2: Some(pio.sm2),
+            sm3: Some(pio.sm3),
+        }
+    }
+}
+
+/// Give the library both PIO blocks.
+pub fn init(pio0: Pio<'static, PIO0>, pio1: Pio<'static, PIO1>) {
+    PIO0_BLOCK.lock(|cell| cell.replace(Some(pio0.into())));
+    PIO1_BLOCK.lock(|cell| cell.replace(Some(pio1.into())));
+}
+
+/// One of a block's state machines, taken out of its [`Block`].
+enum Sm<P: Instance + 'static> {
+    Sm0(StateMachine<'static, P, 0>),
+    Sm1(StateMachine<'static, P, 1>),
+    Sm2(StateMachine<'static, P, 2>),
+    Sm3(StateMachine<'static, P, 3>),
+}
+
+/// Evaluate `$body` with `$sm` bound to whichever state machine `$any` is.
+macro_rules! on_sm {
+    ($any:expr, $sm:ident => $body:expr) => {
+        match $any {
+            Sm::Sm0($sm) => $body,
+            Sm::Sm1($sm) => $body,
+            Sm::Sm2($sm) => $body,
+            Sm::Sm3($sm) => $body,
+        }
+    };
+}
+
+impl<P: Instance> Block<P> {
+    fn take(&mut self, index: u8) -> Option<Sm<P>> {
+        match index {
+            0 => self.sm0.take().map(Sm::Sm0),
+            1 => self.sm1.take().map(Sm::Sm1),
+            2 => self.sm2.take().map(Sm::Sm2),
+            _ => self.sm3.take().map(Sm::Sm3),
+        }
+    }
+
+    fn put_back(&mut self, sm: Sm<P>) {
+        match sm {
+            Sm::Sm0(sm) => self.sm0 = Some(sm),
+            Sm::Sm1(sm) => self.sm1 = Some(sm),
+            Sm::Sm2(sm) => self.sm2 = Some(sm),
+            Sm::Sm3(sm) => self.sm3 = Some(sm),
+        }
+    }
+}
+
+macro_rules! make_pin {
+    ($common:expr, $pin:expr; $($number:literal => $ty:ident),*) => {
+        match $pin {
+            // SAFETY: the pin came out of the registry
+            $($number => $common.make_pio_pin(unsafe { $ty::steal() }),)*
+            _ => core::unreachable!(),
+        }
+    };
+}
+
+/// Hand GPIO `pin`, already claimed, over to the block.
+fn make_pin<P: Instance>(common: &mut Common<'static, P>, pin: u8) -> Pin<'static, P> {
+    make_pin!(common, pin;
+        0 => PIN_0, 1 => PIN_1, 2 => PIN_2, 3 => PIN_3, 4 => PIN_4, 5 => PIN_5,
+        6 => PIN_6, 7 => PIN_7, 8 => PIN_8, 9 => PIN_9, 10 => PIN_10, 11 => PIN_11,
+        12 => PIN_12, 13 => PIN_13, 14 => PIN_14, 15 => PIN_15, 16 => PIN_16, 17 => PIN_17,
+        18 => PIN_18, 19 => PIN_19, 20 => PIN_20, 21 => PIN_21, 22 => PIN_22, 23 => PIN_23,
+        24 => PIN_24, 25 => PIN_25, 26 => PIN_26, 27 => PIN_27, 28 => PIN_28, 29 => PIN_29)
+}
+
+/// The borrowed form `Config` wants pin groups in.
+fn refs<'a, P: Instance>(pins: &'a [Pin<'static, P>]) -> Vec<&'a Pin<'static, P>> {
+    pins.iter().collect()
+}
+
+/// A run of consecutive pins.
+#[derive(Clone, Copy)]
+pub(crate) struct Pins {
+    pub base: u8,
+    pub count: u8,
+}
+
+impl Pins {
+    fn mask(&self) -> u32 {
+        ((1 << self.count) - 1) << self.base
+    }
+}
+
+/// State machine settings, as read from the config table.
+pub(crate) struct Settings {
+    pub out: Option<Pins>,
+    pub set: Option<Pins>,
+    pub sideset: Option<Pins>,
+    pub in_base: Option<u8>,
+    pub jmp: Option<u8>,
+    pub divider: U24F8,
+    /// Shift right, and the autopull threshold if any.
+    pub shift_out: (bool, Option<u8>),
+    /// Shift right, and the autopush threshold if any.
+    pub shift_in: (bool, Option<u8>),
+    pub join: FifoJoin,
+}
+
+impl Settings {
+    /// Every GPIO the state machine will use.
+    pub fn pin_mask(&self) -> u32 {
+        let groups = [self.out, self.set, self.sideset];
+        let singles = [self.in_base, self.jmp].map(|pin| pin.map(|base| Pins { base, count: 1 }));
+        groups
+            .into_iter()
+            .chain(singles)
+            .flatten()
+            .fold(0, |mask, pins| mask | pins.mask())
+    }
+}
+
+unsafe fn check_pins(
+    state: *mut c_void,
+    options: &LuaTable,
+    key: &CStr,
+    count: c_long,
+    max: c_long,
+) -> Option<Pins> {
+    unsafe {
+        let base = options.get::<_, c_long>(key.to_str().unwrap_or_default())?;
+        if !(1..=max).contains(&count) {
+            luaL_error(
+                state,
+                c"pio: %s needs 1..%d pins".as_ptr(),
+                key.as_ptr(),
+                max as c_int,
+            );
+        }
+        if base < 0 || base + count > pins::PIN_COUNT as c_long {
+            luaL_error(state, c"pio: %s pins out of range".as_ptr(), key.as_ptr());
+        }
+        Some(Pins {
+            base: base as u8,
+            count: count as u8,
+        })
+    }
+}
+
+/// Shift direction option `key`: `"left"` or `"right"` (the default).
+unsafe fn check_shift(state: *mut c_void, options: &LuaTable, key: &str) -> bool {
+    match options.get_str(key) {
+        None | Some("right") => true,
+        Some("left") => false,
+        Some(_) => {
+            unsafe { luaL_error(state, c"pio: shift must be \"left\" or \"right\"".as_ptr()) };
+            core::unreachable!()
+        }
+    }
+}
+
+/// Autopull or autopush threshold option `key`, 1 to 32 bits.
+unsafe fn check_threshold(state: *mut c_void, options: &LuaTable, key: &str) -> Option<u8> {
+    let bits = options.get::<_, c_long>(key)?;
+    if !(1..=32).contains(&bits) {
+        unsafe { luaL_error(state, c"pio: threshold must be 1..32 bits".as_ptr()) };
+    }
+    Some(bits as u8)
+}
+
+/// Read the config table at argument `arg`.
+unsafe fn check_settings(state: *mut c_void, arg: c_int, sideset_bits: u8) -> Settings {
+    unsafe {
+        let Some(options) = LuaTable::from_stack(state, arg) else {
+            luaL_error(state, c"pio: config table expected".as_ptr());
+            core::unreachable!()
+        };
+        let out_count = options.get::<_, c_long>("out_count").unwrap_or(1);
+        let set_count = options.get::<_, c_long>("set_count").unwrap_or(1);
+        let out = check_pins(state, &options, c"out", out_count, 32);
+        let set = check_pins(state, &options, c"set", set_count, 5);
+        let sideset = check_pins(state, &options, c"sideset", sideset_bits.into(), 5);
+        if sideset.is_none() && sideset_bits > 0 {
+            luaL_error(
+                state,
+                c"pio: program side-sets, sideset pin needed".as_ptr(),
+            );
+        }
+        let in_base = check_pins(state, &options, c"in", 1, 1).map(|pins| pins.base);
+        let jmp = check_pins(state, &options, c"jmp", 1, 1).map(|pins| pins.base);
+
+        // the divider is 16.8 fixed point, from 1 up
+        let divider = match (
+            options.get::<_, f64>("freq"),
+            options.get::<_, f64>("clkdiv"),
+        ) {
+            (Some(freq), _) if freq > 0.0 => f64::from(clk_sys_freq()) / freq,
+            (Some(_), _) => 0.0,
+            (None, Some(clkdiv)) => clkdiv,
+            (None, None) => 1.0,
+        };
+        if !(1.0..65536.0).contains(&divider) {
+            luaL_error(state, c"pio: clock divider out of range".as_ptr());
+        }
+
+        let join = match options.get_str("join") {
+            None => FifoJoin::Duplex,
+            Some("tx") => FifoJoin::TxOnly,
+            Some("rx") => FifoJoin::RxOnly,
+            Some(_) => {
+                luaL_error(state, c"pio: join must be \"tx\" or \"rx\"".as_ptr());
+                core::unreachable!()
+            }
+        };
+
+        Settings {
+            out,
+            set,
+            sideset,
+            in_base,
+            jmp,
+            divider: U24F8::from_num(divider),
+            shift_out: (
+                check_shift(state, &options, "shift_out"),
+                check_threshold(state, &options, "autopull"),
+            ),
+            shift_in: (
+                check_shift(state, &options, "shift_in"),
+                check_threshold(state, &options, "autopush"),
+            ),
+            join,
+        }
+    }
+}
+
+/// Read the program table at argument `arg`.
+pub(crate) unsafe fn check_program(
+    state: *mut c_void,
+    arg: c_int,
+) -> Program<RP2040_MAX_PROGRAM_SIZE> {
+    unsafe {
+        let Some(table) = LuaTable::from_stack(state, arg) else {
+            luaL_error(state, c"pio: program table expected".as_ptr());
+            core::unreachable!()
+        };
+        let len = table.len();
+        if !(1..=RP2040_MAX_PROGRAM_SIZE).contains(&len) {
+            luaL_error(
+                state,
+                c"pio: program must be 1..%d instructions".as_ptr(),
+                RP2040_MAX_PROGRAM_SIZE as c_int,
+            );
+        }
+        let mut code = ArrayVec::new();
+        for i in 1..=len as c_long {
+            match table.get::<_, c_long>(i) {
+                Some(instr) if (0..=0xffff).contains(&instr) => code.push(instr as u16),
+                _ => {
+                    luaL_error(state, c"pio: bad instruction %d".as_ptr(), i as c_int);
+                }
+            }
+        }
+
+        let last = len as c_long - 1;
+        let field = |key, default| table.get::<_, c_long>(key).unwrap_or(default);
+        let (target, source) = (field("wrap_target", 0), field("wrap", last));
+        if !(0..=last).contains(&target) || !(0..=last).contains(&source) {
+            luaL_error(state, c"pio: wrap outside the program".as_ptr());
+        }
+        let origin = table.get::<_, c_long>("origin");
+        if origin.is_some_and(|origin| !(0..=32 - len as c_long).contains(&origin)) {
+            luaL_error(state, c"pio: origin out of range".as_ptr());
+        }
+        let sideset_bits = field("sideset_bits", 0);
+        let sideset_opt = table.get::<_, bool>("sideset_opt").unwrap_or(false);
+        if !(0..=5 - sideset_opt as c_long).contains(&sideset_bits) {
+            luaL_error(state, c"pio: too many side-set bits".as_ptr());
+        }
+        let sideset_pindirs = table.get::<_, bool>("sideset_pindirs").unwrap_or(false);
+
+        Program {
+            code,
+            origin: origin.map(|origin| origin as u8),
+            wrap: Wrap {
+                source: source as u8,
+                target: target as u8,
+            },
+            side_set: SideSet::new(sideset_opt, sideset_bits as u8, sideset_pindirs),
+            version: PioVersion::V0,
+        }
+    }
+}
+
+/// A state machine running a script's program.
+struct Running<P: Instance + 'static> {
+    sm: Sm<P>,
+    memory: InstanceMemory<'static, P>,
+}
+
+impl<P: HasBlock> Running<P> {
+    /// Take state machine `index`, load `program` and configure it; the
+    /// pins in `settings` must already be claimed. The error is for Lua.
+    fn start(
+        index: u8,
+        program: &Program<RP2040_MAX_PROGRAM_SIZE>,
+        settings: &Settings,
+    ) -> Result<Self, &'static CStr> {
+        P::block().lock(|cell| {
+            let mut cell = cell.borrow_mut();
+            let block = cell.as_mut().ok_or(c"pio: not available")?;
+            let mut sm = block.take(index).ok_or(c"pio: state machine in use")?;
+            let loaded = match block.common.try_load_program(program) {
+                Ok(loaded) => loaded,
+                Err(_) => {
+                    block.put_back(sm);
+                    return Err(c"pio: no room for program");
+                }
+            };
+
+            let mut make_pins = |pins: Option<Pins>| -> Vec<Pin<'static, P>> {
+                pins.map(|pins| pins.base..pins.base + pins.count)
+                    .into_iter()
+                    .flatten()
+                    .map(|pin| make_pin(&mut block.common, pin))
+                    .collect()
+            };
+            let out = make_pins(settings.out);
+            let set = make_pins(settings.set);
+            let sideset = make_pins(settings.sideset);
+            let input = make_pins(settings.in_base.map(|base| Pins { base, count: 1 }));
+            let jmp = make_pins(settings.jmp.map(|base| Pins { base, count: 1 }));
+
+            let mut config = Config::default();
+            config.use_program(&loaded, &refs(&sideset));
+            config.set_out_pins(&refs(&out));
+            config.set_set_pins(&refs(&set));
+            config.set_in_pins(&refs(&input));
+            if let Some(pin) = jmp.first() {
+                config.set_jmp_pin(pin);
+            }
+            config.clock_divider = settings.divider;
+            let shift = |(right, threshold): (bool, Option<u8>)| ShiftConfig {
+                threshold: threshold.unwrap_or(32),
+                direction: if right {
+                    ShiftDirection::Right
+                } else {
+                    ShiftDirection::Left
+                },
+                auto_fill: threshold.is_some(),
+            };
+            config.shift_out = shift(settings.shift_out);
+            config.shift_in = shift(settings.shift_in);
+            config.fifo_join = settings.join;
+
+            let outputs: Vec<_> = [&out, &set, &sideset].into_iter().flatten().collect();
+            on_sm!(&mut sm, sm => {
+                sm.set_config(&config);
+                sm.set_pin_dirs(Direction::Out, &outputs);
+            });
+            Ok(Running {
+                sm,
+                memory: loaded.used_memory,
+            })
+        })
+    }
+
+    /// Disable the state machine and give it and its instruction memory
+    /// back to the block.
+    fn stop(mut self) {
+        on_sm!(&mut self.sm, sm => sm.set_enable(false));
+        P::block().lock(|cell| {
+            if let Some(block) = cell.borrow_mut().as_mut() {
+                // SAFETY: the state machine running the program is stopped
+                unsafe { block.common.free_instr(self.memory) };
+                block.put_back(self.sm);
+            }
+        });
+    }
+
+    fn put(&mut self, value: u32, deadline: Instant) -> bool {
+        on_sm!(&mut self.sm, sm => {
+            let tx = sm.tx();
+            tx.try_push(value) || block_on(with_deadline(deadline, tx.wait_push(value))).is_ok()
+        })
+    }
+
+    fn get(&mut self, deadline: Instant) -> Option<u32> {
+        on_sm!(&mut self.sm, sm => {
+            let rx = sm.rx();
+            rx.try_pull()
+                .or_else(|| block_on(with_deadline(deadline, rx.wait_pull())).ok())
+        })
+    }
+
+    fn set_enable(&mut self, enable: bool) {
+        on_sm!(&mut self.sm, sm => sm.set_enable(enable));
+    }
+
+    fn restart(&mut self) {
+        on_sm!(&mut self.sm, sm => sm.restart());
+    }
+
+    fn dma_push(&mut self, dma: Peri<'_, impl Channel>, words: &[u32]) {
+        on_sm!(&mut self.sm, sm => block_on(sm.tx().dma_push(dma, words, false)));
+    }
+
+    fn exec(&mut self, instr: u16) {
+        // SAFETY: any instruction is fine to run; the worst it does is
+        // confuse the script's own program
+        on_sm!(&mut self.sm, sm => unsafe { sm.exec_instr(instr) });
+    }
+}
+
+enum AnyRunning {
+    Pio0(Running<PIO0>),
+    Pio1(Running<PIO1>),
+}
+
+/// Evaluate `$body` with `$running` bound to whichever block `$any` is on.
+macro_rules! on_block {
+    ($any:expr, $running:ident => $body:expr) => {
+        match $any {
+            AnyRunning::Pio0($running) => $body,
+            AnyRunning::Pio1($running) => $body,
+        }
+    };
+}
+
+/// A state machine opened by a script.
+pub struct LuaPioSm {
+    /// GPIOs claimed for it.
+    pins: u32,
+    /// `None` only while being dropped.
+    running: Option<AnyRunning>,
+}
+
+/// Why a state machine could not be opened.
+pub(crate) enum OpenError {
+    Pin(u8, pins::PinError),
+    Pio(&'static CStr),
+}
+
+impl LuaPioSm {
+    /// Claim the pins in `settings`, then load `program` and set up state
+    /// machine `index` of PIO `block` to run it.
+    pub(crate) fn open(
+        block: u8,
+        index: u8,
+        program: &Program<RP2040_MAX_PROGRAM_SIZE>,
+        settings: &Settings,
+    ) -> Result<Self, OpenError> {
+        let mask = settings.pin_mask();
+        claim_all(mask).map_err(|(pin, e)| OpenError::Pin(pin, e))?;
+        let mut sm = LuaPioSm {
+            pins: mask,
+            running: None,
+        };
+        let running = match block {
+            0 => Running::start(index, program, settings).map(AnyRunning::Pio0),
+            _ => Running::start(index, program, settings).map(AnyRunning::Pio1),
+        };
+        // on error, dropping `sm` hands the pins back
+        sm.running = Some(running.map_err(OpenError::Pio)?);
+        Ok(sm)
+    }
+
+    /// Like [`open`](Self::open), on whichever state machine is free and
+    /// has room for `program` in its block.
+    pub(crate) fn open_any(
+        program: &Program<RP2040_MAX_PROGRAM_SIZE>,
+        settings: &Settings,
+    ) -> Result<Self, OpenError> {
+        for block in 0..2 {
+            for index in 0..4 {
+                match Self::open(block, index, program, settings) {
+                    Err(OpenError::Pio(_)) => continue,
+                    result => return result,
+                }
+            }
+        }
+        Err(OpenError::Pio(c"pio: no free state machine"))
+    }
+
+    /// Feed `words` to the TX FIFO by DMA, returning once the last one is in.
+    pub(crate) fn dma_push(&mut self, dma: Peri<'_, impl Channel>, words: &[u32]) {
+        on_block!(self.running(), running => running.dma_push(dma, words));
+    }
+
+    pub(crate) fn set_enable(&mut self, enable: bool) {
+        on_block!(self.running(), running => running.set_enable(enable));
+    }
+
+    fn running(&mut self) -> &mut AnyRunning {
+        // only Drop takes it
+        self.running.as_mut().unwrap()
+    }
+}
+
+impl Drop for LuaPioSm {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.take() {
+            on_block!(running, running => running.stop());
+        }
+        for pin in 0..pins::PIN_COUNT {
+            if self.pins & (1 << pin) != 0 {
+                pins::release(pin);
+            }
+        }
+    }
+}
+
+impl LuaUserData for LuaPioSm {
+    const NAME: &'static CStr = c"pio.StateMachine";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"put", sm_put),
+        (c"get", sm_get),
+        (c"enable", sm_enable),
+        (c"restart", sm_restart),
+        (c"exec", sm_exec),
+        (c"close", sm_close),
+    ];
+}
+
+/// Claim every pin in `mask`, or none of them.
+fn claim_all(mask: u32) -> Result<(), (u8, pins::PinError)> {
+    for pin in 0..pins::PIN_COUNT {
+        if mask & (1 << pin) == 0 {
+            continue;
+        }
+        if let Err(e) = pins::claim(pin) {
+            for claimed in 0..pin {
+                if mask & (1 << claimed) != 0 {
+                    pins::release(claimed);
+                }
+            }
+            return Err((pin, e));
+        }
+    }
+    Ok(())
+}
+
+/// `pio.open(block, sm, program, config)`
+///
+/// `config` takes pin groups `out` (with `out_count`), `set` (with
+/// `set_count`), `sideset`, `in` and `jmp`; the clock as `freq` in Hz or
+/// `clkdiv`; `shift_out` and `shift_in` (`"left"` or `"right"`);
+/// `autopull` and `autopush` thresholds in bits; and `join` (`"tx"` or
+/// `"rx"`). The state machine starts disabled.
+unsafe extern "C-unwind" fn pio_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let block = luaL_checkinteger(state, 1);
+        let index = luaL_checkinteger(state, 2);
+        if !(0..=1).contains(&block) || !(0..=3).contains(&index) {
+            return luaL_error(
+                state,
+                c"pio: no state machine %d on block %d".as_ptr(),
+                index as c_int,
+                block as c_int,
+            );
+        }
+        let program = check_program(state, 3);
+        let settings = check_settings(
+            state,
+            4,
+            program.side_set.bits() - program.side_set.optional() as u8,
+        );
+
+        match LuaPioSm::open(block as u8, index as u8, &program, &settings) {
+            Ok(sm) => push_userdata(state, sm),
+            Err(OpenError::Pin(pin, e)) => {
+                return luaL_error(
+                    state,
+                    c"pio: gpio %d: %s".as_ptr(),
+                    pin as c_int,
+                    e.message().as_ptr(),
+                );
+            }
+            Err(OpenError::Pio(message)) => return luaL_error(state, message.as_ptr()),
+        }
+    }
+    1
+}
+
+/// Optional timeout argument `arg` in milliseconds, as a deadline. The
+/// default of 0 does not wait.
+unsafe fn check_deadline(state: *mut c_void, arg: c_int) -> Instant {
+    let timeout = unsafe { luaL_optinteger(state, arg, 0) }.max(0);
+    Instant::now() + Duration::from_millis(timeout as u64)
+}
+
+/// `sm:put(word [, timeout_ms])` pushes a 32-bit word onto the TX FIFO,
+/// waiting up to `timeout_ms` for room. Returns whether it went in.
+unsafe extern "C-unwind" fn sm_put(state: *mut c_void) -> c_int {
+    unsafe {
+        let sm = check_userdata::<LuaPioSm>(state, 1);
+        let value = luaL_checkinteger(state, 2) as u32;
+        let deadline = check_deadline(state, 3);
+        let put = on_block!(sm.running(), running => running.put(value, deadline));
+        lua_pushboolean(state, put as c_int);
+    }
+    1
+}
+
+/// `sm:get([timeout_ms])` pops a word off the RX FIFO, waiting up to
+/// `timeout_ms` for one. Returns `nil` if there is none.
+unsafe extern "C-unwind" fn sm_get(state: *mut c_void) -> c_int {
+    unsafe {
+        let sm = check_userdata::<LuaPioSm>(state, 1);
+        let deadline = check_deadline(state, 2);
+        match on_block!(sm.running(), running => running.get(deadline)) {
+            // Lua integers are 32 bits, so the top bit comes out as the sign
+            Some(value) => lua_pushinteger(state, value as i32 as c_long),
+            None => lua_pushnil(state),
+        }
+    }
+    1
+}
+
+/// `sm:enable([on])` starts the state machine, or stops it for `false`.
+unsafe extern "C-unwind" fn sm_enable(state: *mut c_void) -> c_int {
+    unsafe {
+        let sm = check_userdata::<LuaPioSm>(state, 1);
+        let enable = lua_type(state, 2) <= 0 || lua_toboolean(state, 2) != 0;
+        sm.set_enable(enable);
+    }
+    0
+}
+
+/// `sm:restart()` clears the state machine's internal state.
+unsafe extern "C-unwind" fn sm_restart(state: *mut c_void) -> c_int {
+    unsafe {
+        let sm = check_userdata::<LuaPioSm>(state, 1);
+        on_block!(sm.running(), running => running.restart());
+    }
+    0
+}
+
+/// `sm:exec(instr)` runs one instruction right away.
+unsafe extern "C-unwind" fn sm_exec(state: *mut c_void) -> c_int {
+    unsafe {
+        let sm = check_userdata::<LuaPioSm>(state, 1);
+        let instr = luaL_checkinteger(state, 2) as u16;
+        on_block!(sm.running(), running => running.exec(instr));
+    }
+    0
+}
+
+/// `sm:close()` stops the state machine, frees its program and releases its
+/// pins.
+unsafe extern "C-unwind" fn sm_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaPioSm>(state, 1) };
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_pio(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"open", pio_open)]) };
+    1
+}
@@ -0,0 +1,365 @@
+//! `pwm` Lua library.
+//!
+//! ```lua
+//! local led = pwm.open(15, { freq = 1000, duty = 25 })
+//! led:duty(50)
+//! local servo = pwm.servo(16)
+//! servo:angle(90)
+//! ```
+//!
+//! Each GPIO drives channel A (even pins) or B (odd pins) of one of the eight
+//! PWM slices. An object owns its whole slice, so the other pin of the slice
+//! cannot be opened at the same time.
+
+use crate::lua::{
+    LuaCFunction, LuaTable, LuaUserData, check_userdata, close_userdata, lua_pushinteger,
+    luaL_checkinteger, luaL_checknumber, luaL_error, luaL_optinteger, new_lib, push_userdata,
+};
+use crate::pins;
+use core::cell::Cell;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::peripherals::*;
+use embassy_rp::pwm::{Config, Pwm};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use fixed::types::U12F4;
+
+/// Servo pulses repeat every 20 ms.
+const SERVO_FREQ: u32 = 50;
+const SERVO_MIN_US: c_long = 1000;
+const SERVO_MAX_US: c_long = 2000;
+
+// one bit per slice in use
+static SLICES: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+fn slice_of(pin: u8) -> u8 {
+    (pin >> 1) & 7
+}
+
+/// Divider and TOP for `freq` Hz, picking the smallest divider so the duty
+/// cycle gets the most resolution. `None` if the 8.4 divider and 16-bit
+/// counter cannot reach `freq` from the system clock. TOP stays below
+/// 0xffff, so that the compare value for 100%, TOP + 1, fits in 16 bits.
+pub(crate) fn divider_and_top(freq: u32, phase_correct: bool) -> Option<(U12F4, u16)> {
+    if freq == 0 {
+        return None;
+    }
+    // counter steps per period, in 1/16 of a clock to match the divider
+    let mut steps = u64::from(clk_sys_freq()) * 16 / u64::from(freq);
+    if phase_correct {
+        // counts up then down, so twice the steps per period
+        steps /= 2;
+    }
+    let div = steps.div_ceil(0xffff).max(16);
+    // 8 integer bits
+    if div >= 256 * 16 {
+        return None; // too slow
+    }
+    let top = steps / div;
+    if top < 2 {
+        return None; // too fast
+    }
+    Some((U12F4::from_bits(div as u16), (top - 1) as u16))
+}
+
+macro_rules! pwm_output {
+    ($number:expr, $config:expr; $($pin:literal => $slice:ident $ty:ident $new:ident,)*) => {
+        match $number {
+            // SAFETY: the pin came out of the registry, the slice out of SLICES
+            $($pin => unsafe { Pwm::$new($slice::steal(), $ty::steal(), $config) },)*
+            _ => core::unreachable!(),
+        }
+    };
+}
+
+/// Create the driver for `pin` on its slice and channel.
+fn new_output(pin: u8, config: Config) -> Pwm<'static> {
+    pwm_output!(pin, config;
+        0 => PWM_SLICE0 PIN_0 new_output_a,
+        1 => PWM_SLICE0 PIN_1 new_output_b,
+        2 => PWM_SLICE1 PIN_2 new_output_a,
+        3 => PWM_SLICE1 PIN_3 new_output_b,
+        4 => PWM_SLICE2 PIN_4 new_output_a,
+        5 => PWM_SLICE2 PIN_5 new_output_b,
+        6 => PWM_SLICE3 PIN_6 new_output_a,
+        7 => PWM_SLICE3 PIN_7 new_output_b,
+        8 => PWM_SLICE4 PIN_8 new_output_a,
+        9 => PWM_SLICE4 PIN_9 new_output_b,
+        10 => PWM_SLICE5 PIN_10 new_output_a,
+        11 => PWM_SLICE5 PIN_11 new_output_b,
+        12 => PWM_SLICE6 PIN_12 new_output_a,
+        13 => PWM_SLICE6 PIN_13 new_output_b,
+        14 => PWM_SLICE7 PIN_14 new_output_a,
+        15 => PWM_SLICE7 PIN_15 new_output_b,
+        16 => PWM_SLICE0 PIN_16 new_output_a,
+        17 => PWM_SLICE0 PIN_17 new_output_b,
+        18 => PWM_SLICE1 PIN_18 new_output_a,
+        19 => PWM_SLICE1 PIN_19 new_output_b,
+        20 => PWM_SLICE2 PIN_20 new_output_a,
+        21 => PWM_SLICE2 PIN_21 new_output_b,
+        22 => PWM_SLICE3 PIN_22 new_output_a,
+        23 => PWM_SLICE3 PIN_23 new_output_b,
+        24 => PWM_SLICE4 PIN_24 new_output_a,
+        25 => PWM_SLICE4 PIN_25 new_output_b,
+        26 => PWM_SLICE5 PIN_26 new_output_a,
+        27 => PWM_SLICE5 PIN_27 new_output_b,
+        28 => PWM_SLICE6 PIN_28 new_output_a,
+        29 => PWM_SLICE6 PIN_29 new_output_b,
+    )
+}
+
+/// A PWM slice driving one pin.
+pub struct LuaPwm {
+    pin: u8,
+    pwm: Pwm<'static>,
+    config: Config,
+    freq: u32,
+}
+
+impl LuaPwm {
+    fn channel_b(&self) -> bool {
+        self.pin & 1 != 0
+    }
+
+    /// Counter steps per period; a compare value of this keeps the output
+    /// high the whole time.
+    fn period_ticks(&self) -> u32 {
+        u32::from(self.config.top) + 1
+    }
+
+    fn set_compare(&mut self, ticks: u16) {
+        if self.channel_b() {
+            self.config.compare_b = ticks;
+        } else {
+            self.config.compare_a = ticks;
+        }
+        self.pwm.set_config(&self.config);
+    }
+
+    fn compare(&self) -> u16 {
+        if self.channel_b() {
+            self.config.compare_b
+        } else {
+            self.config.compare_a
+        }
+    }
+
+    /// Change the frequency, keeping the duty cycle as a fraction of the
+    /// period.
+    fn set_freq(&mut self, freq: u32) -> Result<(), ()> {
+        let (divider, top) = divider_and_top(freq, self.config.phase_correct).ok_or(())?;
+        let duty =
+            u64::from(self.compare()) * u64::from(top as u32 + 1) / u64::from(self.period_ticks());
+        self.config.divider = divider;
+        self.config.top = top;
+        self.freq = freq;
+        self.set_compare(duty as u16);
+        Ok(())
+    }
+}
+
+impl Drop for LuaPwm {
+    fn drop(&mut self) {
+        let slice = slice_of(self.pin);
+        SLICES.lock(|slices| slices.set(slices.get() & !(1 << slice)));
+        pins::release(self.pin);
+    }
+}
+
+impl LuaUserData for LuaPwm {
+    const NAME: &'static CStr = c"pwm.Pwm";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"duty", pwm_duty),
+        (c"duty_ticks", pwm_duty_ticks),
+        (c"freq", pwm_freq),
+        (c"pulse_us", pwm_pulse_us),
+        (c"angle", pwm_angle),
+        (c"close", pwm_close),
+    ];
+}
+
+/// Claim pin argument 1 and its slice, and start it at `freq` Hz with the
+/// output low.
+unsafe fn open(state: *mut c_void, freq: u32, phase_correct: bool, invert: bool) -> LuaPwm {
+    unsafe {
+        let number = luaL_checkinteger(state, 1);
+        let Some((divider, top)) = divider_and_top(freq, phase_correct) else {
+            luaL_error(
+                state,
+                c"pwm: frequency %d Hz out of range".as_ptr(),
+                freq as c_int,
+            );
+            core::unreachable!()
+        };
+        // the registry hands out AnyPin; the driver below steals the typed pin
+        if let Err(e) = u8::try_from(number)
+            .map_err(|_| pins::PinError::Invalid)
+            .and_then(pins::claim)
+        {
+            luaL_error(
+                state,
+                c"pwm %d: %s".as_ptr(),
+                number as c_int,
+                e.message().as_ptr(),
+            );
+        }
+        let number = number as u8;
+
+        let slice = slice_of(number);
+        let taken = SLICES.lock(|slices| {
+            let taken = slices.get() & (1 << slice) != 0;
+            slices.set(slices.get() | (1 << slice));
+            taken
+        });
+        if taken {
+            pins::release(number);
+            luaL_error(
+                state,
+                c"pwm %d: slice %d in use".as_ptr(),
+                number as c_int,
+                slice as c_int,
+            );
+        }
+
+        let mut config = Config::default();
+        config.divider = divider;
+        config.top = top;
+        config.phase_correct = phase_correct;
+        config.invert_a = invert;
+        config.invert_b = invert;
+        LuaPwm {
+            pin: number,
+            pwm: new_output(number, config.clone()),
+            config,
+            freq,
+        }
+    }
+}
+
+/// `pwm.open(pin [, { freq = hz, duty = percent, phase_correct = bool, invert = bool }])`
+unsafe extern "C-unwind" fn pwm_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let options = LuaTable::from_stack(state, 2);
+        let option = |key: &str| options.as_ref().and_then(|t| t.get::<_, f64>(key));
+        let flag = |key: &str| options.as_ref().and_then(|t| t.get::<_, bool>(key));
+
+        let freq = option("freq").unwrap_or(1000.0) as u32;
+        let mut pwm = open(
+            state,
+            freq,
+            flag("phase_correct").unwrap_or(false),
+            flag("invert").unwrap_or(false),
+        );
+        if let Some(duty) = option("duty") {
+            let ticks = duty_ticks(pwm.period_ticks(), duty);
+            pwm.set_compare(ticks);
+        }
+        push_userdata(state, pwm);
+    }
+    1
+}
+
+/// `pwm.servo(pin)` opens `pin` at 50 Hz for hobby servos.
+unsafe extern "C-unwind" fn pwm_servo(state: *mut c_void) -> c_int {
+    unsafe {
+        let pwm = open(state, SERVO_FREQ, false, false);
+        push_userdata(state, pwm);
+    }
+    1
+}
+
+/// Compare value for `percent` of a `period` tick period, from 0 to
+/// `period` for 100%.
+pub(crate) fn duty_ticks(period: u32, percent: f64) -> u16 {
+    let percent = percent.clamp(0.0, 100.0);
+    (f64::from(period) * percent / 100.0) as u16
+}
+
+/// `pwm:duty(percent)`
+unsafe extern "C-unwind" fn pwm_duty(state: *mut c_void) -> c_int {
+    unsafe {
+        let pwm = check_userdata::<LuaPwm>(state, 1);
+        let ticks = duty_ticks(pwm.period_ticks(), luaL_checknumber(state, 2));
+        pwm.set_compare(ticks);
+    }
+    0
+}
+
+/// `pwm:duty_ticks([ticks])` sets the raw compare value, returning the
+/// period in ticks so scripts can scale against it.
+unsafe extern "C-unwind" fn pwm_duty_ticks(state: *mut c_void) -> c_int {
+    unsafe {
+        let pwm = check_userdata::<LuaPwm>(state, 1);
+        let period = pwm.period_ticks();
+        let ticks = luaL_optinteger(state, 2, pwm.compare() as c_long);
+        if !(0..=period as c_long).contains(&ticks) {
+            return luaL_error(
+                state,
+                c"pwm: %d ticks outside 0..%d".as_ptr(),
+                ticks as c_int,
+                period as c_int,
+            );
+        }
+        // `period` is at most 0xffff, see divider_and_top
+        pwm.set_compare(ticks as u16);
+        lua_pushinteger(state, period as c_long);
+    }
+    1
+}
+
+/// `pwm:freq(hz)`
+unsafe extern "C-unwind" fn pwm_freq(state: *mut c_void) -> c_int {
+    unsafe {
+        let pwm = check_userdata::<LuaPwm>(state, 1);
+        let freq = luaL_checkinteger(state, 2).max(0) as u32;
+        if pwm.set_freq(freq).is_err() {
+            return luaL_error(
+                state,
+                c"pwm: frequency %d Hz out of range".as_ptr(),
+                freq as c_int,
+            );
+        }
+    }
+    0
+}
+
+/// `pwm:pulse_us(us)` sets the high time of each period.
+unsafe extern "C-unwind" fn pwm_pulse_us(state: *mut c_void) -> c_int {
+    unsafe {
+        let pwm = check_userdata::<LuaPwm>(state, 1);
+        let us = luaL_checkinteger(state, 2).max(0) as u64;
+        set_pulse_us(pwm, us);
+    }
+    0
+}
+
+fn set_pulse_us(pwm: &mut LuaPwm, us: u64) {
+    let ticks = us * u64::from(pwm.freq) * u64::from(pwm.period_ticks()) / 1_000_000;
+    pwm.set_compare(ticks.min(u64::from(pwm.period_ticks())) as u16);
+}
+
+/// `pwm:angle(degrees [, min_us [, max_us]])` positions a servo: 0 to 180
+/// degrees maps onto pulses of `min_us` (1000) to `max_us` (2000).
+unsafe extern "C-unwind" fn pwm_angle(state: *mut c_void) -> c_int {
+    unsafe {
+        let pwm = check_userdata::<LuaPwm>(state, 1);
+        let angle = luaL_checknumber(state, 2).clamp(0.0, 180.0);
+        let min_us = luaL_optinteger(state, 3, SERVO_MIN_US);
+        let max_us = luaL_optinteger(state, 4, SERVO_MAX_US);
+        let us = min_us as f64 + (max_us - min_us) as f64 * angle / 180.0;
+        set_pulse_us(pwm, us.max(0.0) as u64);
+    }
+    0
+}
+
+/// `pwm:close()` stops the slice and releases the pin.
+unsafe extern "C-unwind" fn pwm_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaPwm>(state, 1) };
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_pwm(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"open", pwm_open), (c"servo", pwm_servo)]) };
+    1
+}
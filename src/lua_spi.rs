@@ -0,0 +1,532 @@
+//! `spi` Lua library.
+//!
+//! ```lua
+//! local flash = spi.open(0, { sck = 2, mosi = 3, miso = 4, cs = 5, freq = 8000000 })
+//! flash:select()
+//! flash:write("\x03\x00\x00\x00")
+//! local page = flash:read(256)
+//! flash:deselect()
+//! ```
+//!
+//! Data goes in and out as Lua strings. Transfers of [`DMA_MIN`] bytes or
+//! more run on DMA: SPI0 uses DMA_CH3/4 and SPI1 DMA_CH5/6. The optional chip
+//! select is a plain GPIO claimed from the [`pins`](crate::pins) registry and
+//! is asserted around each call, or from `select()` to `deselect()`.
+
+use crate::lua::{
+    LuaCFunction, LuaTable, LuaUserData, check_bytes, check_stack, check_userdata, close_userdata,
+    lua_pushlstring, luaL_checkinteger, luaL_error, luaL_optinteger, new_lib, push_buffer,
+    push_userdata,
+};
+use crate::pins;
+use core::cell::Cell;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_futures::block_on;
+use embassy_rp::Peri;
+use embassy_rp::clocks::clk_peri_freq;
+use embassy_rp::gpio::{AnyPin, Level, Output};
+use embassy_rp::peripherals::*;
+use embassy_rp::spi::{Async, ClkPin, Config, Error, MosiPin, Phase, Polarity, Spi};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Transfers shorter than this are done by the CPU; setting up DMA costs
+/// more than it saves.
+pub const DMA_MIN: usize = 16;
+const DEFAULT_FREQ: u32 = 1_000_000;
+/// Most bytes a single `read` or `transfer` will return.
+const MAX_READ: usize = 4096;
+
+// one bit per controller in use
+static BUSES: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+enum Bus {
+    Spi0(Spi<'static, SPI0, Async>),
+    Spi1(Spi<'static, SPI1, Async>),
+}
+
+/// Evaluate `$body` with `$spi` bound to whichever controller `$bus` holds.
+macro_rules! on_bus {
+    ($bus:expr, $spi:ident => $body:expr) => {
+        match $bus {
+            Bus::Spi0($spi) => $body,
+            Bus::Spi1($spi) => $body,
+        }
+    };
+}
+
+/// Build `mod $module` with a `new` that types the pin numbers one at a
+/// time and creates the driver for `$peri`, full duplex or, without a MISO
+/// pin, transmit only.
+macro_rules! builder {
+    ($module:ident: $peri:ident, $tx_dma:ident, $rx_dma:ident;
+     sck: $($sck:literal => $sck_ty:ident),*;
+     mosi: $($mosi:literal => $mosi_ty:ident),*;
+     miso: $($miso:literal => $miso_ty:ident),*) => {
+        mod $module {
+            use super::*;
+
+            pub fn new(
+                sck: u8,
+                mosi: u8,
+                miso: Option<u8>,
+                config: Config,
+            ) -> Spi<'static, $peri, Async> {
+                // SAFETY: the pin came out of the registry
+                match sck {
+                    $($sck => with_sck(unsafe { $sck_ty::steal() }, mosi, miso, config),)*
+                    _ => core::unreachable!(),
+                }
+            }
+
+            fn with_sck(
+                sck: Peri<'static, impl ClkPin<$peri>>,
+                mosi: u8,
+                miso: Option<u8>,
+                config: Config,
+            ) -> Spi<'static, $peri, Async> {
+                // SAFETY: the pin came out of the registry
+                match mosi {
+                    $($mosi => with_mosi(sck, unsafe { $mosi_ty::steal() }, miso, config),)*
+                    _ => core::unreachable!(),
+                }
+            }
+
+            fn with_mosi(
+                sck: Peri<'static, impl ClkPin<$peri>>,
+                mosi: Peri<'static, impl MosiPin<$peri>>,
+                miso: Option<u8>,
+                config: Config,
+            ) -> Spi<'static, $peri, Async> {
+                // SAFETY: the pins came out of the registry, the controller
+                // out of BUSES, and its DMA channels are used by nothing else
+                unsafe {
+                    let (spi, tx_dma) = ($peri::steal(), $tx_dma::steal());
+                    match miso {
+                        None => Spi::new_txonly(spi, sck, mosi, tx_dma, config),
+                        $(Some($miso) => {
+                            let rx_dma = $rx_dma::steal();
+                            Spi::new(spi, sck, mosi, $miso_ty::steal(), tx_dma, rx_dma, config)
+                        })*
+                        _ => core::unreachable!(),
+                    }
+                }
+            }
+        }
+    };
+}
+
+builder!(spi0: SPI0, DMA_CH3, DMA_CH4;
+    sck: 2 => PIN_2, 6 => PIN_6, 18 => PIN_18, 22 => PIN_22;
+    mosi: 3 => PIN_3, 7 => PIN_7, 19 => PIN_19, 23 => PIN_23;
+    miso: 0 => PIN_0, 4 => PIN_4, 16 => PIN_16, 20 => PIN_20);
+builder!(spi1: SPI1, DMA_CH5, DMA_CH6;
+    sck: 10 => PIN_10, 14 => PIN_14, 26 => PIN_26;
+    mosi: 11 => PIN_11, 15 => PIN_15, 27 => PIN_27;
+    miso: 8 => PIN_8, 12 => PIN_12, 24 => PIN_24, 28 => PIN_28);
+
+/// Pin functions repeat every four pins (RX, CSn, SCK, TX) and the
+/// controllers alternate every eight.
+fn pin_fits(bus: u8, pin: c_long, function: c_long) -> bool {
+    (0..pins::PIN_COUNT as c_long).contains(&pin)
+        && (pin >> 3) & 1 == bus as c_long
+        && pin & 3 == function
+}
+
+/// An SPI controller and its pins, claimed by a script.
+pub struct LuaSpi {
+    index: u8,
+    /// SCK, MOSI and MISO.
+    pins: [Option<u8>; 3],
+    cs: Option<(u8, Output<'static>)>,
+    /// Set between `select()` and `deselect()`.
+    held: bool,
+    lsb_first: bool,
+    config: Config,
+    bus: Bus,
+}
+
+impl LuaSpi {
+    fn has_miso(&self) -> bool {
+        self.pins[2].is_some()
+    }
+
+    fn begin(&mut self) {
+        if let Some((_, cs)) = &mut self.cs {
+            cs.set_low();
+        }
+    }
+
+    fn end(&mut self) {
+        if self.held {
+            return;
+        }
+        if let Some((_, cs)) = &mut self.cs {
+            cs.set_high();
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.begin();
+        let result = on_bus!(&mut self.bus, spi => {
+            if data.len() < DMA_MIN {
+                spi.blocking_write(data)
+            } else {
+                block_on(spi.write(data))
+            }
+        });
+        self.end();
+        result
+    }
+
+    /// Clock out `data`, replacing it with what comes back.
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        self.begin();
+        let result = on_bus!(&mut self.bus, spi => {
+            if data.len() < DMA_MIN {
+                spi.blocking_transfer_in_place(data)
+            } else {
+                block_on(spi.transfer_in_place(data))
+            }
+        });
+        self.end();
+        result
+    }
+}
+
+impl Drop for LuaSpi {
+    fn drop(&mut self) {
+        BUSES.lock(|buses| buses.set(buses.get() & !(1 << self.index)));
+        let cs = self.cs.as_ref().map(|(pin, _)| *pin);
+        for pin in self.pins.into_iter().chain([cs]).flatten() {
+            pins::release(pin);
+        }
+    }
+}
+
+impl LuaUserData for LuaSpi {
+    const NAME: &'static CStr = c"spi.Bus";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"write", spi_write),
+        (c"read", spi_read),
+        (c"transfer", spi_transfer),
+        (c"select", spi_select),
+        (c"deselect", spi_deselect),
+        (c"config", spi_config),
+        (c"close", spi_close),
+    ];
+}
+
+/// Apply `freq`, `mode` (0 to 3) and `order` (`"msb"` or `"lsb"`) from the
+/// options table to `config` and `lsb_first`.
+unsafe fn read_options(
+    state: *mut c_void,
+    options: &LuaTable,
+    config: &mut Config,
+    lsb_first: &mut bool,
+) {
+    unsafe {
+        if let Some(freq) = options.get::<_, c_long>("freq") {
+            // the prescalers divide clk_peri by 2 to 254 * 256
+            let max = clk_peri_freq() / 2;
+            let min = clk_peri_freq().div_ceil(254 * 256);
+            if !(min as c_long..=max as c_long).contains(&freq) {
+                luaL_error(
+                    state,
+                    c"spi: frequency %d Hz out of range".as_ptr(),
+                    freq as c_int,
+                );
+            }
+            config.frequency = freq as u32;
+        }
+        if let Some(mode) = options.get::<_, c_long>("mode") {
+            if !(0..=3).contains(&mode) {
+                luaL_error(state, c"spi: mode must be 0..3".as_ptr());
+            }
+            config.polarity = if mode & 2 == 0 {
+                Polarity::IdleLow
+            } else {
+                Polarity::IdleHigh
+            };
+            config.phase = if mode & 1 == 0 {
+                Phase::CaptureOnFirstTransition
+            } else {
+                Phase::CaptureOnSecondTransition
+            };
+        }
+        match options.get_str("order") {
+            None => {}
+            Some("msb") => *lsb_first = false,
+            Some("lsb") => *lsb_first = true,
+            Some(_) => {
+                luaL_error(state, c"spi: order must be \"msb\" or \"lsb\"".as_ptr());
+            }
+        }
+    }
+}
+
+/// `spi.open(bus, { sck = pin, mosi = pin [, miso = pin] [, cs = pin]
+/// [, freq = hz] [, mode = 0..3] [, order = "msb" | "lsb"] })`
+unsafe extern "C-unwind" fn spi_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let index = luaL_checkinteger(state, 1);
+        if !(0..=1).contains(&index) {
+            return luaL_error(state, c"spi: no bus %d".as_ptr(), index as c_int);
+        }
+        let index = index as u8;
+        let Some(options) = LuaTable::from_stack(state, 2) else {
+            return luaL_error(state, c"spi: options table expected".as_ptr());
+        };
+
+        let mut config = Config::default();
+        config.frequency = DEFAULT_FREQ;
+        let mut lsb_first = false;
+        read_options(state, &options, &mut config, &mut lsb_first);
+
+        // SCK, MOSI, MISO and CS, with their pin functions
+        let mut wanted = [None; 4];
+        let roles: [(&CStr, c_long); 4] = [(c"sck", 2), (c"mosi", 3), (c"miso", 0), (c"cs", -1)];
+        for (i, (key, function)) in roles.into_iter().enumerate() {
+            let Some(pin) = options.get::<_, c_long>(key.to_str().unwrap_or_default()) else {
+                if i < 2 {
+                    return luaL_error(state, c"spi: %s pin required".as_ptr(), key.as_ptr());
+                }
+                continue;
+            };
+            if function >= 0 && !pin_fits(index, pin, function) {
+                return luaL_error(
+                    state,
+                    c"spi %d: gpio %d cannot be %s".as_ptr(),
+                    index as c_int,
+                    pin as c_int,
+                    key.as_ptr(),
+                );
+            }
+            wanted[i] = Some(pin);
+        }
+
+        let taken = BUSES.lock(|buses| {
+            let taken = buses.get() & (1 << index) != 0;
+            buses.set(buses.get() | (1 << index));
+            taken
+        });
+        if taken {
+            return luaL_error(state, c"spi %d in use".as_ptr(), index as c_int);
+        }
+        let mut claimed: [Option<(u8, Peri<'static, AnyPin>)>; 4] = [const { None }; 4];
+        for (slot, pin) in claimed.iter_mut().zip(wanted) {
+            let Some(pin) = pin else { continue };
+            match u8::try_from(pin)
+                .map_err(|_| pins::PinError::Invalid)
+                .and_then(|number| Ok((number, pins::claim(number)?)))
+            {
+                Ok(claim) => *slot = Some(claim),
+                Err(e) => {
+                    for (number, _) in claimed.iter().flatten() {
+                        pins::release(*number);
+                    }
+                    BUSES.lock(|buses| buses.set(buses.get() & !(1 << index)));
+                    return luaL_error(
+                        state,
+                        c"spi %d: gpio %d: %s".as_ptr(),
+                        index as c_int,
+                        pin as c_int,
+                        e.message().as_ptr(),
+                    );
+                }
+            }
+        }
+
+        let [Some((sck, _)), Some((mosi, _)), miso, cs] = claimed else {
+            core::unreachable!()
+        };
+        let miso = miso.map(|(number, _)| number);
+        let bus = match index {
+            0 => Bus::Spi0(spi0::new(sck, mosi, miso, config.clone())),
+            _ => Bus::Spi1(spi1::new(sck, mosi, miso, config.clone())),
+        };
+        push_userdata(
+            state,
+            LuaSpi {
+                index,
+                pins: [Some(sck), Some(mosi), miso],
+                cs: cs.map(|(number, pin)| (number, Output::new(pin, Level::High))),
+                held: false,
+                lsb_first,
+                config,
+                bus,
+            },
+        );
+    }
+    1
+}
+
+/// Push a `len` byte buffer to work in, to be pushed as the result string
+/// afterwards.
+unsafe fn work_buffer<'a>(state: *mut c_void, len: usize) -> &'a mut [u8] {
+    unsafe {
+        check_stack(state, 2);
+        push_buffer(state, len)
+    }
+}
+
+fn reverse_bits(data: &mut [u8]) {
+    for byte in data {
+        *byte = byte.reverse_bits();
+    }
+}
+
+unsafe fn check_duplex<'a>(state: *mut c_void, arg: c_int) -> &'a mut LuaSpi {
+    unsafe {
+        let spi = check_userdata::<LuaSpi>(state, arg);
+        if !spi.has_miso() {
+            luaL_error(
+                state,
+                c"spi %d has no miso pin".as_ptr(),
+                spi.index as c_int,
+            );
+        }
+        spi
+    }
+}
+
+unsafe fn check(state: *mut c_void, result: Result<(), Error>) {
+    if result.is_err() {
+        unsafe { luaL_error(state, c"spi: transfer failed".as_ptr()) };
+    }
+}
+
+/// `spi:write(data)`
+unsafe extern "C-unwind" fn spi_write(state: *mut c_void) -> c_int {
+    unsafe {
+        let spi = check_userdata::<LuaSpi>(state, 1);
+        let data = check_bytes(state, 2);
+        let result = if spi.lsb_first {
+            // the controller only shifts MSB first
+            let buffer = work_buffer(state, data.len());
+            buffer.copy_from_slice(data);
+            reverse_bits(buffer);
+            spi.write(buffer)
+        } else {
+            spi.write(data)
+        };
+        check(state, result);
+    }
+    0
+}
+
+/// `spi:read(len [, fill])` clocks out `len` copies of `fill` (default 0)
+/// and returns what came back.
+unsafe extern "C-unwind" fn spi_read(state: *mut c_void) -> c_int {
+    unsafe {
+        let spi = check_duplex(state, 1);
+        let len = luaL_checkinteger(state, 2);
+        let fill = luaL_optinteger(state, 3, 0) as u8;
+        if !(1..=MAX_READ as c_long).contains(&len) {
+            return luaL_error(
+                state,
+                c"spi: read must be 1..%d bytes".as_ptr(),
+                MAX_READ as c_int,
+            );
+        }
+        let buffer = work_buffer(state, len as usize);
+        buffer.fill(if spi.lsb_first {
+            fill.reverse_bits()
+        } else {
+            fill
+        });
+        check(state, spi.transfer(buffer));
+        if spi.lsb_first {
+            reverse_bits(buffer);
+        }
+        lua_pushlstring(state, buffer.as_ptr(), buffer.len());
+    }
+    1
+}
+
+/// `spi:transfer(data)` clocks out `data` and returns the bytes clocked in
+/// meanwhile.
+unsafe extern "C-unwind" fn spi_transfer(state: *mut c_void) -> c_int {
+    unsafe {
+        let spi = check_duplex(state, 1);
+        let data = check_bytes(state, 2);
+        if data.len() > MAX_READ {
+            return luaL_error(
+                state,
+                c"spi: transfer must be at most %d bytes".as_ptr(),
+                MAX_READ as c_int,
+            );
+        }
+        let buffer = work_buffer(state, data.len());
+        buffer.copy_from_slice(data);
+        if spi.lsb_first {
+            reverse_bits(buffer);
+        }
+        check(state, spi.transfer(buffer));
+        if spi.lsb_first {
+            reverse_bits(buffer);
+        }
+        lua_pushlstring(state, buffer.as_ptr(), buffer.len());
+    }
+    1
+}
+
+unsafe fn check_cs<'a>(state: *mut c_void, arg: c_int) -> &'a mut LuaSpi {
+    unsafe {
+        let spi = check_userdata::<LuaSpi>(state, arg);
+        if spi.cs.is_none() {
+            luaL_error(state, c"spi %d has no cs pin".as_ptr(), spi.index as c_int);
+        }
+        spi
+    }
+}
+
+/// `spi:select()` asserts chip select until `deselect()`, so several calls
+/// form one transaction.
+unsafe extern "C-unwind" fn spi_select(state: *mut c_void) -> c_int {
+    unsafe {
+        let spi = check_cs(state, 1);
+        spi.held = true;
+        spi.begin();
+    }
+    0
+}
+
+/// `spi:deselect()`
+unsafe extern "C-unwind" fn spi_deselect(state: *mut c_void) -> c_int {
+    unsafe {
+        let spi = check_cs(state, 1);
+        spi.held = false;
+        spi.end();
+    }
+    0
+}
+
+/// `spi:config({ [freq = hz] [, mode = 0..3] [, order = "msb" | "lsb"] })`
+unsafe extern "C-unwind" fn spi_config(state: *mut c_void) -> c_int {
+    unsafe {
+        let spi = check_userdata::<LuaSpi>(state, 1);
+        let Some(options) = LuaTable::from_stack(state, 2) else {
+            return luaL_error(state, c"spi: options table expected".as_ptr());
+        };
+        // into copies, so that a bad option leaves the settings untouched
+        let mut config = spi.config.clone();
+        let mut lsb_first = spi.lsb_first;
+        read_options(state, &options, &mut config, &mut lsb_first);
+        spi.config = config;
+        spi.lsb_first = lsb_first;
+        on_bus!(&mut spi.bus, bus => bus.set_config(&spi.config));
+    }
+    0
+}
+
+/// `spi:close()` releases the controller and its pins.
+unsafe extern "C-unwind" fn spi_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaSpi>(state, 1) };
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_spi(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"open", spi_open)]) };
+    1
+}
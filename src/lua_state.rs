@@ -0,0 +1,370 @@
+//! Lua states with their own memory budget and libraries.
+//!
+//! Several [`LuaState`]s can be open at once, e.g. a trusted system state
+//! with every library and a user state with a few and little memory. They
+//! share nothing but the hardware; values go from one to another as
+//! messages through the [`msg`](crate::lua_msg) library, addressed by the
+//! states' ids.
+//!
+//! A state for untrusted scripts also gets a [`Sandbox`]: it only sees the
+//! globals the sandbox lists, cannot load bytecode, and is stopped with an
+//! error after running its share of instructions.
+
+use crate::alloc::{free, realloc};
+use crate::lua::{
+    LUA_ERRRUN, LUA_MASKCOUNT, LUA_OK, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS, LUA_TNIL, LUA_TNUMBER,
+    LUA_TTABLE, LuaCFunction, check_bytes, check_stack, lua_atpanic, lua_call, lua_close,
+    lua_createtable, lua_getallocf, lua_getfield, lua_getglobal, lua_gettop, lua_newstate,
+    lua_pcall, lua_pop, lua_pushcclosure, lua_pushinteger, lua_pushlightuserdata, lua_pushlstring,
+    lua_pushnil, lua_pushvalue, lua_rawget, lua_rawseti, lua_rotate, lua_setfield, lua_sethook,
+    lua_settop, lua_setupvalue, lua_tointegerx, lua_touserdata, lua_type, lua_upvalueindex,
+    luaL_checklstring, luaL_error, luaL_loadbufferx, open_libs, report,
+};
+use crate::lua_event::{self, HOOK_INTERVAL};
+use core::cell::Cell;
+use core::ffi::{CStr, c_char, c_int, c_long, c_void};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Most states open at once; ids run from 0 to `MAX_STATES - 1`.
+pub const MAX_STATES: usize = 4;
+
+/// Registry field holding the state's id.
+const ID: &CStr = c"state.id";
+
+// bit n set while id n is taken
+static OPEN: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// What a new state gets.
+pub struct Config<'a> {
+    /// Most bytes the state may have allocated at once. It must leave room
+    /// for the libraries.
+    pub memory: usize,
+    /// Standard libraries to open, e.g. [`STD_LIBS`](crate::lua::STD_LIBS).
+    pub std_libs: &'a [(&'a CStr, LuaCFunction)],
+    /// Board libraries to open, e.g. [`BOARD_LIBS`](crate::lua::BOARD_LIBS).
+    pub board_libs: &'a [(&'a CStr, LuaCFunction)],
+    /// Restrictions for untrusted scripts, or `None` for a trusted state.
+    pub sandbox: Option<Sandbox<'a>>,
+}
+
+/// What a sandboxed state's scripts may do. Its memory is capped by
+/// [`Config::memory`] like any state's.
+pub struct Sandbox<'a> {
+    /// The globals scripts see, e.g. [`SANDBOX_GLOBALS`]; the rest, like
+    /// `io` or the peripheral libraries, are out of their reach. A listed
+    /// `load` only takes source text, and a listed `setmetatable` refuses
+    /// finalizers.
+    pub globals: &'a [&'a CStr],
+    /// VM instructions scripts may run, counted in steps of
+    /// [`HOOK_INTERVAL`], until [`LuaState::reset_instructions`]. 0 for no
+    /// limit.
+    pub instructions: u32,
+}
+
+/// The base functions and libraries safe to hand to untrusted scripts.
+pub const SANDBOX_GLOBALS: &[&CStr] = &[
+    c"_G",
+    c"_VERSION",
+    c"assert",
+    c"error",
+    c"getmetatable",
+    c"ipairs",
+    c"load",
+    c"next",
+    c"pairs",
+    c"pcall",
+    c"print",
+    c"rawequal",
+    c"rawget",
+    c"rawlen",
+    c"rawset",
+    c"select",
+    c"setmetatable",
+    c"tonumber",
+    c"tostring",
+    c"type",
+    c"xpcall",
+    c"math",
+    c"string",
+    c"table",
+    c"time",
+    c"task",
+    c"msg",
+];
+
+/// A state's allocations and instructions so far, checked against its
+/// limits.
+struct Budget {
+    used: Cell<usize>,
+    limit: usize,
+    instructions: Cell<u32>,
+    instruction_limit: u32,
+}
+
+/// `lua_Alloc` that refuses to grow a state's memory past its budget.
+unsafe extern "C" fn budget_alloc(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void {
+    let budget = unsafe { &*(ud as *const Budget) };
+    // for a new block osize is the kind of object, not a size
+    let old = if ptr.is_null() { 0 } else { osize };
+    if nsize == 0 {
+        free(ptr);
+        budget.used.set(budget.used.get() - old);
+        return core::ptr::null_mut();
+    }
+    let used = budget.used.get() - old + nsize;
+    // shrinking must not fail, whatever the budget
+    if nsize > old && used > budget.limit {
+        return core::ptr::null_mut();
+    }
+    let block = realloc(ptr, nsize);
+    if !block.is_null() {
+        budget.used.set(used);
+    }
+    block
+}
+
+/// Count hook of a state with an instruction limit. It also dispatches
+/// events, as the state has no room for
+/// [`install_dispatch_hook`](lua_event::install_dispatch_hook)'s.
+unsafe extern "C-unwind" fn limit_hook(state: *mut c_void, _ar: *mut c_void) {
+    unsafe {
+        let mut ud = core::ptr::null_mut();
+        lua_getallocf(state, &mut ud);
+        let budget = &*(ud as *const Budget);
+        let left = budget.instructions.get();
+        if left == 0 {
+            // from now on stop at every instruction, so that the error gets
+            // out of any pcall in the script
+            lua_sethook(state, Some(limit_hook), LUA_MASKCOUNT, 1);
+            luaL_error(state, c"instruction limit reached".as_ptr());
+            return;
+        }
+        budget
+            .instructions
+            .set(left.saturating_sub(HOOK_INTERVAL as u32));
+        lua_event::dispatch(state);
+    }
+}
+
+/// `load(chunk [, chunkname [, mode [, env]]])` for sandboxes: the standard
+/// `load` for a string `chunk`, except that it must be source text whatever
+/// `mode` says.
+unsafe extern "C-unwind" fn load_text(state: *mut c_void) -> c_int {
+    unsafe {
+        let args = lua_gettop(state);
+        let chunk = check_bytes(state, 1);
+        let name = if lua_type(state, 2) > 0 {
+            luaL_checklstring(state, 2, core::ptr::null_mut())
+        } else {
+            chunk.as_ptr() as *const c_char
+        };
+        check_stack(state, 2);
+        let status = luaL_loadbufferx(
+            state,
+            chunk.as_ptr() as *const c_char,
+            chunk.len(),
+            name,
+            c"t".as_ptr(),
+        );
+        if status != LUA_OK {
+            // fail, followed by the message
+            lua_pushnil(state);
+            lua_rotate(state, -2, 1);
+            return 2;
+        }
+        if args >= 4 {
+            lua_pushvalue(state, 4);
+            if lua_setupvalue(state, -2, 1).is_null() {
+                lua_pop(state, 1);
+            }
+        }
+    }
+    1
+}
+
+/// `setmetatable(table, metatable)` for sandboxes: the standard one, which
+/// it gets as upvalue 1, except that `metatable` may not have a `__gc`. The
+/// collector runs finalizers with hooks off, so one could loop past the
+/// instruction limit.
+unsafe extern "C-unwind" fn set_metatable(state: *mut c_void) -> c_int {
+    unsafe {
+        check_stack(state, 2);
+        if lua_type(state, 2) == LUA_TTABLE {
+            lua_pushlstring(state, c"__gc".as_ptr(), 4);
+            let finalizer = lua_rawget(state, 2) != LUA_TNIL;
+            lua_pop(state, 1);
+            if finalizer {
+                luaL_error(
+                    state,
+                    c"bad argument #2 to 'setmetatable' (__gc not allowed)".as_ptr(),
+                );
+            }
+        }
+        lua_settop(state, 2);
+        lua_pushvalue(state, lua_upvalueindex(1));
+        lua_rotate(state, 1, 1);
+        lua_call(state, 2, 1);
+    }
+    1
+}
+
+/// Replace the globals with a table of only `names` from them.
+unsafe fn restrict_globals(state: *mut c_void, names: &[&CStr]) {
+    unsafe {
+        check_stack(state, 2);
+        lua_createtable(state, 0, names.len() as c_int);
+        for name in names {
+            match name.to_bytes() {
+                b"_G" => lua_pushvalue(state, -1),
+                b"load" => lua_pushcclosure(state, load_text, 0),
+                b"setmetatable" => {
+                    lua_getglobal(state, name.as_ptr());
+                    lua_pushcclosure(state, set_metatable, 1);
+                }
+                _ => {
+                    lua_getglobal(state, name.as_ptr());
+                }
+            }
+            lua_setfield(state, -2, name.as_ptr());
+        }
+        lua_rawseti(state, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+    }
+}
+
+/// Raise an error outside any protected call: report it and stop.
+unsafe extern "C-unwind" fn at_panic(state: *mut c_void) -> c_int {
+    unsafe { report(state, LUA_ERRRUN) };
+    core::panic!("unprotected Lua error")
+}
+
+/// Record the id passed as argument 2, then open the libraries of the
+/// [`Config`] passed as a light userdata in argument 1 and apply its sandbox.
+unsafe extern "C-unwind" fn open_config(state: *mut c_void) -> c_int {
+    unsafe {
+        lua_setfield(state, LUA_REGISTRYINDEX, ID.as_ptr());
+        let config = &*(lua_touserdata(state, 1) as *const Config);
+        open_libs(state, config.std_libs);
+        open_libs(state, config.board_libs);
+        if let Some(sandbox) = &config.sandbox {
+            restrict_globals(state, sandbox.globals);
+        }
+    }
+    0
+}
+
+fn take_id() -> Option<u8> {
+    OPEN.lock(|open| {
+        let id = (0..MAX_STATES as u8).find(|id| open.get() & (1 << id) == 0)?;
+        open.set(open.get() | (1 << id));
+        Some(id)
+    })
+}
+
+fn release_id(id: u8) {
+    OPEN.lock(|open| open.set(open.get() & !(1 << id)));
+}
+
+/// Whether a state with id `id` is open.
+pub fn is_open(id: u8) -> bool {
+    OPEN.lock(|open| open.get() & (1 << id) != 0)
+}
+
+/// The id of the [`LuaState`] that `state` belongs to.
+pub unsafe fn id(state: *mut c_void) -> Option<u8> {
+    unsafe {
+        check_stack(state, 1);
+        let found = lua_getfield(state, LUA_REGISTRYINDEX, ID.as_ptr()) == LUA_TNUMBER;
+        let id = lua_tointegerx(state, -1, core::ptr::null_mut()) as u8;
+        lua_pop(state, 1);
+        found.then_some(id)
+    }
+}
+
+/// A Lua state, closed when dropped.
+pub struct LuaState {
+    state: *mut c_void,
+    id: u8,
+    // boxed so the allocator's pointer to it stays put
+    budget: Box<Budget>,
+}
+
+impl LuaState {
+    /// Open a state as `config` says. Returns `None` if [`MAX_STATES`] are
+    /// already open or the libraries do not fit in its memory.
+    pub fn new(config: &Config) -> Option<Self> {
+        let id = take_id()?;
+        let instruction_limit = config.sandbox.as_ref().map_or(0, |s| s.instructions);
+        let budget = Box::new(Budget {
+            used: Cell::new(0),
+            limit: config.memory,
+            instructions: Cell::new(instruction_limit),
+            instruction_limit,
+        });
+        let ud = &*budget as *const Budget as *mut c_void;
+        let state = unsafe { lua_newstate(budget_alloc, ud) };
+        if state.is_null() {
+            release_id(id);
+            return None;
+        }
+        // dropping it from here on closes the state and releases the id
+        let lua = Self { state, id, budget };
+        unsafe {
+            lua_atpanic(state, at_panic);
+            // nothing here allocates, so nothing can fail outside the call
+            lua_pushcclosure(state, open_config, 0);
+            lua_pushlightuserdata(state, config as *const Config as *mut c_void);
+            lua_pushinteger(state, c_long::from(id));
+            if report(state, lua_pcall(state, 2, 0, 0)) != LUA_OK {
+                return None;
+            }
+            if instruction_limit > 0 {
+                // threads the state creates inherit the hook
+                lua_sethook(state, Some(limit_hook), LUA_MASKCOUNT, HOOK_INTERVAL);
+            }
+        }
+        Some(lua)
+    }
+
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.state
+    }
+
+    /// The id other states send messages to.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Bytes the state has allocated.
+    pub fn memory_used(&self) -> usize {
+        self.budget.used.get()
+    }
+
+    /// Give a sandboxed state its full [`Sandbox::instructions`] again,
+    /// e.g. before running the next script.
+    pub fn reset_instructions(&self) {
+        self.budget.instructions.set(self.budget.instruction_limit);
+        if self.budget.instruction_limit > 0 {
+            // back from stopping at every instruction
+            unsafe { lua_sethook(self.state, Some(limit_hook), LUA_MASKCOUNT, HOOK_INTERVAL) };
+        }
+    }
+}
+
+impl Drop for LuaState {
+    fn drop(&mut self) {
+        unsafe { lua_close(self.state) };
+        crate::lua_msg::clear(self.id);
+        crate::lua_gpio::stop_edges(self.id);
+        lua_event::clear(self.id);
+        release_id(self.id);
+    }
+}
@@ -0,0 +1,256 @@
+//! `sys` Lua library: the board and the firmware.
+//!
+//! ```lua
+//! print(sys.version(), sys.flash_id(), sys.uptime())
+//! print(sys.temperature(), sys.clocks().sys, sys.heap())
+//! print(sys.reset_reason())
+//! local crash = sys.last_crash()
+//! if crash then print(crash.message, crash.file, crash.line) end
+//! sys.watchdog(5000)
+//! while true do
+//!     work()
+//!     sys.feed()
+//! end
+//! ```
+//!
+//! The hardware watchdog handed to [`init`] is fed by a task on core 0's
+//! interrupt executor, so the board resets if that core panics or hangs.
+//! Once a script arms `sys.watchdog`, the task also stops feeding it when
+//! the script goes too long without `sys.feed()`. `sys.reset()` goes
+//! through the same task, so the next boot sees a forced reset.
+
+use crate::lua::{
+    LuaTable, check_stack, lua_pushinteger, lua_pushlstring, lua_pushnil, lua_pushnumber,
+    lua_rawseti, lua_setfield, luaL_checkinteger, luaL_error, new_lib,
+};
+use crate::{crash, lua_adc};
+use arrayvec::ArrayString;
+use core::cell::Cell;
+use core::ffi::{c_int, c_long, c_void};
+use core::fmt::Write;
+use embassy_executor::SendSpawner;
+use embassy_futures::select::{Either, select};
+use embassy_rp::clocks;
+use embassy_rp::watchdog::{ResetReason, Watchdog};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+// the script's timeout and when it next has to feed the watchdog, while it
+// has one armed
+static SCRIPT_WATCH: Mutex<CriticalSectionRawMutex, Cell<Option<(Duration, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+static RESET_REASON: Mutex<CriticalSectionRawMutex, Cell<&'static str>> =
+    Mutex::new(Cell::new("power-on"));
+
+static FLASH_ID: Mutex<CriticalSectionRawMutex, Cell<[u8; 8]>> = Mutex::new(Cell::new([0; 8]));
+
+// asks the watchdog task to reset the board
+static RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Record why the board last reset and the flash's unique id, and start
+/// feeding `watchdog` from a task on `spawner`. The board resets if the task
+/// goes `timeout` without feeding it.
+pub fn init(spawner: SendSpawner, mut watchdog: Watchdog, timeout: Duration, flash_id: [u8; 8]) {
+    let reason = match watchdog.reset_reason() {
+        None => "power-on",
+        Some(ResetReason::TimedOut) => "watchdog",
+        Some(_) => "forced",
+    };
+    RESET_REASON.lock(|cell| cell.set(reason));
+    FLASH_ID.lock(|cell| cell.set(flash_id));
+    // a core halted by the debugger is not a hang
+    watchdog.pause_on_debug(true);
+    spawner.spawn(feed_watchdog(watchdog, timeout)).unwrap();
+}
+
+/// Why the board last reset: `"power-on"`, `"watchdog"` or `"forced"`.
+pub fn reset_reason() -> &'static str {
+    RESET_REASON.lock(|cell| cell.get())
+}
+
+/// Whether the watchdog may still be fed at `now`: unless a script has
+/// armed `sys.watchdog` and missed its deadline.
+pub(crate) fn script_fed(now: Instant) -> bool {
+    let watch = SCRIPT_WATCH.lock(|cell| cell.get());
+    watch.is_none_or(|(_, deadline)| now < deadline)
+}
+
+#[embassy_executor::task]
+async fn feed_watchdog(mut watchdog: Watchdog, timeout: Duration) {
+    watchdog.start(timeout);
+    loop {
+        if script_fed(Instant::now()) {
+            watchdog.feed();
+        }
+        if let Either::Second(()) = select(Timer::after(timeout / 4), RESET.wait()).await {
+            watchdog.trigger_reset();
+        }
+    }
+}
+
+/// `sys.watchdog(ms)` resets the board unless `sys.feed()` is called at
+/// least every `ms` milliseconds from now on. 0 disarms it again.
+unsafe extern "C-unwind" fn sys_watchdog(state: *mut c_void) -> c_int {
+    let ms = unsafe { luaL_checkinteger(state, 1) };
+    let watch = (ms > 0).then(|| {
+        let timeout = Duration::from_millis(ms as u64);
+        (timeout, Instant::now() + timeout)
+    });
+    SCRIPT_WATCH.lock(|cell| cell.set(watch));
+    0
+}
+
+/// `sys.feed()` restarts the time `sys.watchdog` allows.
+unsafe extern "C-unwind" fn sys_feed(_state: *mut c_void) -> c_int {
+    SCRIPT_WATCH.lock(|cell| {
+        if let Some((timeout, _)) = cell.get() {
+            cell.set(Some((timeout, Instant::now() + timeout)));
+        }
+    });
+    0
+}
+
+/// `sys.reset_reason()` returns why the board last reset: `"power-on"`,
+/// `"watchdog"`, or `"forced"` by software.
+unsafe extern "C-unwind" fn sys_reset_reason(state: *mut c_void) -> c_int {
+    unsafe { push_str(state, reset_reason()) };
+    1
+}
+
+/// `sys.last_crash()` returns the panic that caused the last reset as
+/// `{message = , file = , line = , stack = {addr, ...}}`, or `nil`.
+unsafe extern "C-unwind" fn sys_last_crash(state: *mut c_void) -> c_int {
+    let Some(report) = crash::last() else {
+        unsafe { lua_pushnil(state) };
+        return 1;
+    };
+    unsafe {
+        check_stack(state, 2);
+        let table = LuaTable::new(state, 0, 4);
+        table.set("message", report.message());
+        table.set("file", report.file());
+        table.set("line", report.line() as c_long);
+        LuaTable::new(state, report.stack().len() as c_int, 0);
+        for (i, addr) in report.stack().iter().enumerate() {
+            lua_pushinteger(state, *addr as c_long);
+            lua_rawseti(state, -2, i as c_long + 1);
+        }
+        lua_setfield(state, -2, c"stack".as_ptr());
+    }
+    1
+}
+
+/// Push `s` as a Lua string.
+unsafe fn push_str(state: *mut c_void, s: &str) {
+    unsafe { lua_pushlstring(state, s.as_ptr(), s.len()) };
+}
+
+/// `sys.version()` returns the firmware version and the embassy executor's.
+unsafe extern "C-unwind" fn sys_version(state: *mut c_void) -> c_int {
+    unsafe {
+        check_stack(state, 2);
+        push_str(state, env!("CARGO_PKG_VERSION"));
+        push_str(state, env!("EMBASSY_EXECUTOR_VERSION"));
+    }
+    2
+}
+
+/// `sys.flash_id()` returns the flash chip's unique id as 16 hex digits.
+unsafe extern "C-unwind" fn sys_flash_id(state: *mut c_void) -> c_int {
+    let id = u64::from_be_bytes(FLASH_ID.lock(|cell| cell.get()));
+    // on the stack, as pushing the string can raise an error
+    let mut hex = ArrayString::<16>::new();
+    write!(hex, "{id:016x}").ok();
+    unsafe { push_str(state, &hex) };
+    1
+}
+
+/// `sys.uptime()` returns the seconds since boot.
+unsafe extern "C-unwind" fn sys_uptime(state: *mut c_void) -> c_int {
+    let micros = Instant::now().as_micros();
+    unsafe { lua_pushnumber(state, micros as f64 / 1e6) };
+    1
+}
+
+/// `sys.clocks()` returns the clock frequencies in Hz as
+/// `{sys = , peri = , usb = , adc = , ref = }`.
+unsafe extern "C-unwind" fn sys_clocks(state: *mut c_void) -> c_int {
+    unsafe {
+        check_stack(state, 1);
+        let table = LuaTable::new(state, 0, 5);
+        table.set("sys", clocks::clk_sys_freq() as c_long);
+        table.set("peri", clocks::clk_peri_freq() as c_long);
+        table.set("usb", clocks::clk_usb_freq() as c_long);
+        table.set("adc", clocks::clk_adc_freq() as c_long);
+        table.set("ref", clocks::clk_ref_freq() as c_long);
+    }
+    1
+}
+
+/// `sys.heap()` returns the bytes of the heap in use, the most in use since
+/// boot, and the heap's size.
+unsafe extern "C-unwind" fn sys_heap(state: *mut c_void) -> c_int {
+    let (used, peak) = crate::alloc::heap_usage();
+    unsafe {
+        check_stack(state, 3);
+        lua_pushinteger(state, used as c_long);
+        lua_pushinteger(state, peak as c_long);
+        lua_pushinteger(state, crate::alloc::HEAP_SIZE as c_long);
+    }
+    3
+}
+
+/// `sys.temperature()` returns the core temperature in degrees C.
+unsafe extern "C-unwind" fn sys_temperature(state: *mut c_void) -> c_int {
+    let Some(celsius) = lua_adc::core_celsius() else {
+        return unsafe { luaL_error(state, c"sys: temperature sensor not available".as_ptr()) };
+    };
+    unsafe { lua_pushnumber(state, celsius) };
+    1
+}
+
+/// Reset the board through the watchdog task, so the next boot sees a
+/// forced reset.
+pub fn reset() -> ! {
+    RESET.signal(());
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// `sys.reset()` resets the board.
+unsafe extern "C-unwind" fn sys_reset(_state: *mut c_void) -> c_int {
+    reset()
+}
+
+/// `sys.bootsel()` reboots into the USB bootloader, as if BOOTSEL were held.
+unsafe extern "C-unwind" fn sys_bootsel(_state: *mut c_void) -> c_int {
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_sys(state: *mut c_void) -> c_int {
+    unsafe {
+        new_lib(
+            state,
+            &[
+                (c"watchdog", sys_watchdog),
+                (c"feed", sys_feed),
+                (c"reset_reason", sys_reset_reason),
+                (c"last_crash", sys_last_crash),
+                (c"version", sys_version),
+                (c"flash_id", sys_flash_id),
+                (c"uptime", sys_uptime),
+                (c"clocks", sys_clocks),
+                (c"heap", sys_heap),
+                (c"temperature", sys_temperature),
+                (c"reset", sys_reset),
+                (c"bootsel", sys_bootsel),
+            ],
+        );
+    }
+    1
+}
@@ -0,0 +1,143 @@
+//! `task` Lua library: cooperative tasks run by
+//! [`AsyncLua`](crate::lua_async::AsyncLua).
+//!
+//! ```lua
+//! task.spawn(function()
+//!     while true do led:toggle() time.sleep_ms(500) end
+//! end, "blink")
+//! task.spawn(function()
+//!     while true do
+//!         local _, level = task.wait("button")
+//!         print("button", level)
+//!     end
+//! end, "button")
+//! gpio.on_edge(14, "both", function(pin, level) task.signal("button", level) end, "up")
+//! ps()
+//! ```
+//!
+//! Tasks only switch where one yields: in `task.yield`, `task.wait`, or a
+//! library call that waits, like `time.sleep_ms`. Any Lua value can be an
+//! event; `task.signal` wakes the tasks waiting for one equal to it.
+//!
+//! Opening the library also sets the global `ps()`, which lists the tasks on
+//! the console.
+
+use crate::console_ldd::console_write_blocking;
+use crate::lua::{
+    LUA_TFUNCTION, LuaCFunction, LuaTable, LuaUserData, check_bytes, check_stack, check_userdata,
+    lua_gettop, lua_pushcclosure, lua_pushinteger, lua_pushvalue, lua_rawseti, lua_setglobal,
+    lua_type, luaL_checkany, luaL_checkinteger, luaL_checktype, luaL_error, new_lib, push_userdata,
+};
+use crate::lua_async;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_time::{Duration, Instant};
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// `task.spawn(fn [, name])` runs `fn()` as a new task, named `name` or after
+/// its id. Returns the id.
+unsafe extern "C-unwind" fn task_spawn(state: *mut c_void) -> c_int {
+    unsafe {
+        luaL_checktype(state, 1, LUA_TFUNCTION);
+        let name = (lua_type(state, 2) > 0).then(|| check_bytes(state, 2));
+        check_stack(state, 1);
+        lua_pushvalue(state, 1);
+        let Some(id) = lua_async::spawn(state, name) else {
+            return luaL_error(state, c"task: no scheduler running".as_ptr());
+        };
+        lua_pushinteger(state, id);
+    }
+    1
+}
+
+/// `task.yield()` lets the other tasks run.
+unsafe extern "C-unwind" fn task_yield(state: *mut c_void) -> c_int {
+    unsafe { lua_async::yield_task(state) }
+}
+
+/// `task.wait(event [, timeout_ms])` waits for `task.signal(event, ...)`.
+/// Returns `true` and the signalled values, or `false` on timeout.
+unsafe extern "C-unwind" fn task_wait(state: *mut c_void) -> c_int {
+    unsafe {
+        luaL_checkany(state, 1);
+        let deadline = if lua_type(state, 2) > 0 {
+            let timeout = luaL_checkinteger(state, 2).max(0);
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        } else {
+            None
+        };
+        lua_async::wait(state, 1, deadline)
+    }
+}
+
+/// `task.signal(event, ...)` wakes the tasks waiting for `event`, handing
+/// them the other arguments. Returns how many there were.
+unsafe extern "C-unwind" fn task_signal(state: *mut c_void) -> c_int {
+    unsafe {
+        luaL_checkany(state, 1);
+        let woken = lua_async::signal(state, 1, lua_gettop(state) - 1);
+        lua_pushinteger(state, woken as c_long);
+    }
+    1
+}
+
+/// [`lua_async::list`], kept in a userdata while `task.list` builds its
+/// table, so that the list is freed even if Lua runs out of memory.
+struct TaskList(Vec<(c_long, String, &'static str)>);
+
+impl LuaUserData for TaskList {
+    const NAME: &'static CStr = c"task.list";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[];
+}
+
+/// `task.list()` returns a table of `{id = , name = , status = }`, one per
+/// task. `status` is `"running"`, `"ready"`, `"awaiting"` (a library call)
+/// or `"waiting"` (`task.wait`).
+unsafe extern "C-unwind" fn task_list(state: *mut c_void) -> c_int {
+    unsafe {
+        check_stack(state, 3);
+        push_userdata(state, TaskList(lua_async::list(state)));
+        let tasks = &check_userdata::<TaskList>(state, -1).0;
+        LuaTable::new(state, tasks.len() as c_int, 0);
+        for (i, (id, name, status)) in tasks.iter().enumerate() {
+            let entry = LuaTable::new(state, 0, 3);
+            entry.set("id", *id);
+            entry.set("name", name.as_str());
+            entry.set("status", *status);
+            lua_rawseti(state, -2, i as c_long + 1);
+        }
+    }
+    1
+}
+
+/// `ps()` prints the tasks, one per line.
+unsafe extern "C-unwind" fn ps(state: *mut c_void) -> c_int {
+    let tasks = unsafe { lua_async::list(state) };
+    console_write_blocking("   ID STATUS   NAME\n").ok();
+    for (id, name, status) in tasks {
+        console_write_blocking(&format!("{id:5} {status:8} {name}\n")).ok();
+    }
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_task(state: *mut c_void) -> c_int {
+    unsafe {
+        new_lib(
+            state,
+            &[
+                (c"spawn", task_spawn),
+                (c"yield", task_yield),
+                (c"wait", task_wait),
+                (c"signal", task_signal),
+                (c"list", task_list),
+            ],
+        );
+        check_stack(state, 1);
+        lua_pushcclosure(state, ps, 0);
+        lua_setglobal(state, c"ps".as_ptr());
+    }
+    1
+}
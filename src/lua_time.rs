@@ -0,0 +1,214 @@
+//! `time` Lua library.
+//!
+//! ```lua
+//! local start = time.ticks_ms()
+//! time.sleep_ms(500)
+//! print(time.ticks_ms() - start)
+//!
+//! local blink = time.every(250, function() led:toggle() end)
+//! time.after(2000, function() time.cancel(blink) end)
+//! ```
+//!
+//! In scripts run by [`AsyncLua`](crate::lua_async::AsyncLua), `sleep_ms`
+//! awaits an embassy timer, so the executor's other tasks carry on while the
+//! script sleeps. Elsewhere, e.g. inside a callback, it waits on the spot.
+//!
+//! The runner calls [`fire_due`] for `after` and `every` callbacks while the
+//! script waits, and after it returns for as long as timers are pending.
+//! Ticks are 32-bit Lua integers that wrap, so compare them by subtracting.
+
+use crate::lua::{
+    LUA_REGISTRYINDEX, LUA_TFUNCTION, LuaCFunction, LuaUserData, StackGuard, check_stack,
+    lua_gettop, lua_pushboolean, lua_pushinteger, lua_pushnil, lua_pushvalue, lua_rawgeti,
+    lua_rawseti, lua_settop, luaL_checkinteger, luaL_checktype, luaL_error, luaL_getsubtable,
+    new_lib, pcall_traceback, registry_userdata, report,
+};
+use crate::lua_async::await_future;
+use core::ffi::{CStr, c_int, c_long, c_void};
+use embassy_time::{Duration, Instant, Timer};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Registry table mapping a timer id to its callback.
+const CALLBACKS: &CStr = c"time.callbacks";
+/// Registry field holding the state's [`Timers`].
+const TIMERS: &CStr = c"time.timers";
+
+struct Pending {
+    id: c_long,
+    due: Instant,
+    /// `None` for a one-shot `after` timer.
+    period: Option<Duration>,
+}
+
+/// A state's timers, kept in its registry.
+#[derive(Default)]
+pub struct Timers {
+    pending: Vec<Pending>,
+    last_id: c_long,
+}
+
+impl LuaUserData for Timers {
+    const NAME: &'static CStr = c"time.Timers";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[];
+}
+
+unsafe fn timers<'a>(state: *mut c_void) -> &'a mut Timers {
+    unsafe { registry_userdata(state, TIMERS) }
+}
+
+/// When the next timer of `state` is due, if it has any.
+pub unsafe fn next_due(state: *mut c_void) -> Option<Instant> {
+    unsafe { timers(state) }
+        .pending
+        .iter()
+        .map(|timer| timer.due)
+        .min()
+}
+
+/// Call the callback of every timer that is due, rescheduling `every`
+/// timers and forgetting `after` ones.
+pub unsafe fn fire_due(state: *mut c_void) {
+    unsafe {
+        let guard = StackGuard::new(state);
+        loop {
+            let now = Instant::now();
+            let timers = timers(state);
+            let Some(i) = timers.pending.iter().position(|timer| timer.due <= now) else {
+                break;
+            };
+            let id = timers.pending[i].id;
+            let once = match timers.pending[i].period {
+                Some(period) => {
+                    let timer = &mut timers.pending[i];
+                    // skip ticks missed while a script ran rather than
+                    // calling back for each of them
+                    timer.due = (timer.due + period).max(now);
+                    false
+                }
+                None => {
+                    timers.pending.swap_remove(i);
+                    true
+                }
+            };
+
+            let top = lua_gettop(state);
+            check_stack(state, 3);
+            luaL_getsubtable(state, LUA_REGISTRYINDEX, CALLBACKS.as_ptr());
+            let callback = lua_rawgeti(state, -1, id);
+            if once {
+                lua_pushnil(state);
+                lua_rawseti(state, -3, id);
+            }
+            if callback == LUA_TFUNCTION {
+                report(state, pcall_traceback(state, 0, 0));
+            }
+            lua_settop(state, top);
+        }
+        guard.check();
+    }
+}
+
+/// `time.ticks_ms()`
+unsafe extern "C-unwind" fn time_ticks_ms(state: *mut c_void) -> c_int {
+    unsafe { lua_pushinteger(state, Instant::now().as_millis() as c_long) };
+    1
+}
+
+/// `time.ticks_us()`
+unsafe extern "C-unwind" fn time_ticks_us(state: *mut c_void) -> c_int {
+    unsafe { lua_pushinteger(state, Instant::now().as_micros() as c_long) };
+    1
+}
+
+/// `time.sleep_ms(ms)`
+unsafe extern "C-unwind" fn time_sleep_ms(state: *mut c_void) -> c_int {
+    unsafe {
+        let ms = luaL_checkinteger(state, 1).max(0);
+        let wake = Instant::now() + Duration::from_millis(ms as u64);
+        await_future(state, Timer::at(wake), |_, ()| 0)
+    }
+}
+
+/// Schedule the callback at argument 2 to run after argument 1
+/// milliseconds, then every `period` if given. Returns the timer id.
+unsafe fn schedule(state: *mut c_void, period: bool) -> c_int {
+    unsafe {
+        let ms = luaL_checkinteger(state, 1);
+        // a period of 0 would keep fire_due calling back forever
+        if period && ms <= 0 {
+            return luaL_error(state, c"time: bad period %d".as_ptr(), ms as c_int);
+        }
+        let ms = ms.max(0);
+        luaL_checktype(state, 2, LUA_TFUNCTION);
+        let delay = Duration::from_millis(ms as u64);
+
+        let timers = timers(state);
+        timers.last_id = timers.last_id.wrapping_add(1).max(1);
+        let id = timers.last_id;
+        timers.pending.push(Pending {
+            id,
+            due: Instant::now() + delay,
+            period: period.then_some(delay),
+        });
+
+        let top = lua_gettop(state);
+        check_stack(state, 2);
+        luaL_getsubtable(state, LUA_REGISTRYINDEX, CALLBACKS.as_ptr());
+        lua_pushvalue(state, 2);
+        lua_rawseti(state, -2, id);
+        lua_settop(state, top);
+        lua_pushinteger(state, id);
+    }
+    1
+}
+
+/// `time.after(ms, fn)` calls `fn()` once, `ms` from now. Returns an id for
+/// `time.cancel`.
+unsafe extern "C-unwind" fn time_after(state: *mut c_void) -> c_int {
+    unsafe { schedule(state, false) }
+}
+
+/// `time.every(ms, fn)` calls `fn()` every `ms`, which must be positive,
+/// until cancelled. Returns an id for `time.cancel`.
+unsafe extern "C-unwind" fn time_every(state: *mut c_void) -> c_int {
+    unsafe { schedule(state, true) }
+}
+
+/// `time.cancel(id)` stops a timer. Returns whether it was still pending.
+unsafe extern "C-unwind" fn time_cancel(state: *mut c_void) -> c_int {
+    unsafe {
+        let id = luaL_checkinteger(state, 1);
+        let timers = timers(state);
+        let before = timers.pending.len();
+        timers.pending.retain(|timer| timer.id != id);
+        let cancelled = timers.pending.len() != before;
+
+        let top = lua_gettop(state);
+        check_stack(state, 2);
+        luaL_getsubtable(state, LUA_REGISTRYINDEX, CALLBACKS.as_ptr());
+        lua_pushnil(state);
+        lua_rawseti(state, -2, id);
+        lua_settop(state, top);
+        lua_pushboolean(state, cancelled as c_int);
+    }
+    1
+}
+
+pub unsafe extern "C-unwind" fn luaopen_time(state: *mut c_void) -> c_int {
+    unsafe {
+        new_lib(
+            state,
+            &[
+                (c"ticks_ms", time_ticks_ms),
+                (c"ticks_us", time_ticks_us),
+                (c"sleep_ms", time_sleep_ms),
+                (c"after", time_after),
+                (c"every", time_every),
+                (c"cancel", time_cancel),
+            ],
+        );
+    }
+    1
+}
@@ -0,0 +1,420 @@
+//! `uart` Lua library for UART1. The console keeps UART0.
+//!
+//! ```lua
+//! local gps = uart.open(4, 5, { baud = 9600 })
+//! local line = gps:readline(1000)
+//! gps:write("$PMTK220,1000*1F\r\n")
+//! print(gps:available())
+//! ```
+//!
+//! Received bytes are buffered whether or not a script is reading: the
+//! driver's interrupt fills its ring buffer, and [`rx_task`], on the spawner
+//! given to [`init`], moves them on into a [`Pipe`] the port methods read
+//! from. The interrupt is handled on that spawner's core, whichever core
+//! opened the port. In scripts run by [`AsyncLua`](crate::lua_async::AsyncLua), reads
+//! that have to wait for data await it without holding up other tasks.
+
+use crate::lua::{
+    LuaCFunction, LuaTable, LuaUserData, check_bytes, check_stack, check_userdata, close_userdata,
+    lua_pushinteger, lua_pushlstring, lua_pushnil, luaL_checkinteger, luaL_error, luaL_optinteger,
+    new_lib, push_userdata,
+};
+use crate::lua_async::await_future;
+use crate::pins;
+use core::cell::Cell;
+use core::ffi::{CStr, c_int, c_long, c_void};
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+use defmt::*;
+use embassy_executor::SendSpawner;
+use embassy_futures::select::{Either, select};
+use embassy_rp::Peri;
+use embassy_rp::bind_interrupts;
+use embassy_rp::interrupt::typelevel::{Interrupt, UART1_IRQ};
+use embassy_rp::peripherals::*;
+use embassy_rp::uart::{
+    BufferedInterruptHandler, BufferedUart, BufferedUartRx, BufferedUartTx, Config, DataBits,
+    Parity, StopBits, TxPin,
+};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, with_deadline};
+use embedded_io_async::Read;
+
+bind_interrupts!(struct Irqs {
+    UART1_IRQ => BufferedInterruptHandler<UART1>;
+});
+
+const DEFAULT_BAUD: c_long = 115_200;
+/// Bytes received but not yet read by the script.
+const RX_PIPE: usize = 1024;
+/// Most bytes a single `read` will return.
+const MAX_READ: c_long = 1024;
+/// `readline` hands back what it has once a line gets this long.
+const MAX_LINE: usize = 256;
+
+static SPAWNER: Mutex<CriticalSectionRawMutex, Cell<Option<SendSpawner>>> =
+    Mutex::new(Cell::new(None));
+
+// set from open until rx_task has let go of the port
+static OPEN: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+static RX: Pipe<CriticalSectionRawMutex, RX_PIPE> = Pipe::new();
+// tells rx_task to stop and release the port
+static RX_STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// the driver's own ring buffers, lent to it while OPEN is set
+static mut TX_BUFFER: [u8; 256] = [0; 256];
+static mut RX_BUFFER: [u8; 64] = [0; 64];
+
+/// Give the library the spawner for its receive task.
+pub fn init(spawner: SendSpawner) {
+    SPAWNER.lock(|cell| cell.set(Some(spawner)));
+}
+
+macro_rules! with_rx {
+    ($($pin:literal => $ty:ident),*) => {
+        /// Finish building the driver once the TX pin is typed.
+        fn with_rx(
+            tx: Peri<'static, impl TxPin<UART1>>,
+            rx: u8,
+            config: Config,
+        ) -> BufferedUart {
+            // SAFETY: the pins came out of the registry and OPEN was clear,
+            // so the port and its buffers are ours
+            unsafe {
+                let tx_buffer = &mut *(&raw mut TX_BUFFER);
+                let rx_buffer = &mut *(&raw mut RX_BUFFER);
+                match rx {
+                    $($pin => BufferedUart::new(
+                        UART1::steal(),
+                        tx,
+                        $ty::steal(),
+                        Irqs,
+                        tx_buffer,
+                        rx_buffer,
+                        config,
+                    ),)*
+                    _ => core::unreachable!(),
+                }
+            }
+        }
+    };
+}
+
+with_rx!(5 => PIN_5, 9 => PIN_9, 21 => PIN_21, 25 => PIN_25);
+
+/// Create the driver for a pair of pins already checked by [`pins_fit`].
+fn new_uart(tx: u8, rx: u8, config: Config) -> BufferedUart {
+    // SAFETY: the pin came out of the registry
+    unsafe {
+        match tx {
+            4 => with_rx(PIN_4::steal(), rx, config),
+            8 => with_rx(PIN_8::steal(), rx, config),
+            20 => with_rx(PIN_20::steal(), rx, config),
+            24 => with_rx(PIN_24::steal(), rx, config),
+            _ => core::unreachable!(),
+        }
+    }
+}
+
+/// UART1 has TX on GPIO 4, 8, 20 and 24, each with RX on the next pin.
+fn pins_fit(tx: c_long, rx: c_long) -> bool {
+    [4, 8, 20, 24].contains(&tx) && [5, 9, 21, 25].contains(&rx)
+}
+
+/// Moves received bytes into [`RX`] until told to stop, then releases the
+/// port and its pins.
+#[embassy_executor::task]
+async fn rx_task(mut rx: BufferedUartRx, pins: [u8; 2]) {
+    // SAFETY: the driver is set up, and uart.open let go of the interrupt
+    unsafe { UART1_IRQ::enable() };
+    let mut chunk = [0; 32];
+    loop {
+        let receive = async {
+            match rx.read(&mut chunk).await {
+                Ok(n) => RX.write_all(&chunk[..n]).await,
+                Err(e) => warn!("uart: receive error {}", e),
+            }
+        };
+        if let Either::Second(()) = select(receive, RX_STOP.wait()).await {
+            break;
+        }
+    }
+    drop(rx);
+    RX.clear();
+    for pin in pins {
+        pins::release(pin);
+    }
+    OPEN.lock(|open| open.set(false));
+}
+
+/// UART1 opened by a script.
+pub struct LuaUart {
+    tx: BufferedUartTx,
+    /// Received bytes taken out of [`RX`] but not returned yet.
+    pending: Vec<u8>,
+}
+
+impl LuaUart {
+    /// Length of the first line in `pending`, its terminator included, or
+    /// all of it once it is [`MAX_LINE`] long.
+    fn line_len(&self) -> Option<usize> {
+        match self.pending.iter().position(|&b| b == b'\n') {
+            Some(end) => Some(end + 1),
+            None => (self.pending.len() >= MAX_LINE).then_some(self.pending.len()),
+        }
+    }
+}
+
+/// Take up to `len` received bytes, waiting until `deadline` for them to
+/// arrive.
+async fn receive(len: usize, deadline: Instant) -> Vec<u8> {
+    let mut buffer = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match with_deadline(deadline, RX.read(&mut buffer[read..])).await {
+            Ok(n) => read += n,
+            Err(_) => break,
+        }
+    }
+    buffer.truncate(read);
+    buffer
+}
+
+/// Take received bytes until a line ends or `room` bytes have come, waiting
+/// until `deadline` for them to arrive.
+async fn receive_line(room: usize, deadline: Instant) -> Vec<u8> {
+    let mut chunk = [0; 32];
+    let mut received = Vec::new();
+    while received.len() < room && !received.contains(&b'\n') {
+        let want = chunk.len().min(room - received.len());
+        match with_deadline(deadline, RX.read(&mut chunk[..want])).await {
+            Ok(n) => received.extend_from_slice(&chunk[..n]),
+            Err(_) => break,
+        }
+    }
+    received
+}
+
+impl Drop for LuaUart {
+    fn drop(&mut self) {
+        // rx_task releases the port once it has dropped its half
+        RX_STOP.signal(());
+    }
+}
+
+impl LuaUserData for LuaUart {
+    const NAME: &'static CStr = c"uart.Port";
+    const METHODS: &'static [(&'static CStr, LuaCFunction)] = &[
+        (c"write", port_write),
+        (c"read", port_read),
+        (c"readline", port_readline),
+        (c"available", port_available),
+        (c"close", port_close),
+    ];
+}
+
+/// Apply `baud`, `bits` (5 to 8), `parity` (`"none"`, `"even"` or `"odd"`)
+/// and `stop` (1 or 2) from the options table to `config`.
+unsafe fn read_options(state: *mut c_void, options: &LuaTable, config: &mut Config) {
+    unsafe {
+        if let Some(baud) = options.get::<_, c_long>("baud") {
+            if baud <= 0 {
+                luaL_error(state, c"uart: bad baud rate %d".as_ptr(), baud as c_int);
+            }
+            config.baudrate = baud as u32;
+        }
+        config.data_bits = match options.get::<_, c_long>("bits") {
+            None | Some(8) => DataBits::DataBits8,
+            Some(7) => DataBits::DataBits7,
+            Some(6) => DataBits::DataBits6,
+            Some(5) => DataBits::DataBits5,
+            Some(_) => {
+                luaL_error(state, c"uart: bits must be 5..8".as_ptr());
+                core::unreachable!()
+            }
+        };
+        config.parity = match options.get_str("parity") {
+            None | Some("none") => Parity::ParityNone,
+            Some("even") => Parity::ParityEven,
+            Some("odd") => Parity::ParityOdd,
+            Some(_) => {
+                luaL_error(state, c"uart: parity must be none, even or odd".as_ptr());
+                core::unreachable!()
+            }
+        };
+        config.stop_bits = match options.get::<_, c_long>("stop") {
+            None | Some(1) => StopBits::STOP1,
+            Some(2) => StopBits::STOP2,
+            Some(_) => {
+                luaL_error(state, c"uart: stop must be 1 or 2".as_ptr());
+                core::unreachable!()
+            }
+        };
+    }
+}
+
+/// `uart.open(tx, rx [, { baud = 115200, bits = 8, parity = "none", stop = 1 }])`
+unsafe extern "C-unwind" fn uart_open(state: *mut c_void) -> c_int {
+    unsafe {
+        let tx = luaL_checkinteger(state, 1);
+        let rx = luaL_checkinteger(state, 2);
+        if !pins_fit(tx, rx) {
+            return luaL_error(
+                state,
+                c"uart: gpio %d/%d are not UART1 TX/RX".as_ptr(),
+                tx as c_int,
+                rx as c_int,
+            );
+        }
+        let (tx, rx) = (tx as u8, rx as u8);
+        let mut config = Config::default();
+        config.baudrate = DEFAULT_BAUD as u32;
+        if let Some(options) = LuaTable::from_stack(state, 3) {
+            read_options(state, &options, &mut config);
+        }
+        let Some(spawner) = SPAWNER.lock(|cell| cell.get()) else {
+            return luaL_error(state, c"uart: not available".as_ptr());
+        };
+
+        if OPEN.lock(|open| open.replace(true)) {
+            return luaL_error(state, c"uart: UART1 in use".as_ptr());
+        }
+        let claimed =
+            pins::claim(tx).and_then(|_| pins::claim(rx).inspect_err(|_| pins::release(tx)));
+        if let Err(e) = claimed {
+            OPEN.lock(|open| open.set(false));
+            return luaL_error(state, c"uart: %s".as_ptr(), e.message().as_ptr());
+        }
+
+        let (uart_tx, uart_rx) = new_uart(tx, rx, config).split();
+        // the driver enabled its interrupt on this core, which may be core 1;
+        // rx_task takes it over, as the wakes the handler sends only reach an
+        // interrupt executor from its own core
+        UART1_IRQ::disable();
+        RX.clear();
+        RX_STOP.reset();
+        if spawner.spawn(rx_task(uart_rx, [tx, rx])).is_err() {
+            // the receive half went down with the unspawned task
+            pins::release(tx);
+            pins::release(rx);
+            OPEN.lock(|open| open.set(false));
+            return luaL_error(state, c"uart: cannot start receiver".as_ptr());
+        }
+        push_userdata(
+            state,
+            LuaUart {
+                tx: uart_tx,
+                pending: Vec::new(),
+            },
+        );
+    }
+    1
+}
+
+/// Optional timeout argument `arg` in milliseconds, as a deadline. The
+/// default of 0 takes only what has already arrived.
+unsafe fn check_deadline(state: *mut c_void, arg: c_int) -> Instant {
+    let timeout = unsafe { luaL_optinteger(state, arg, 0) }.max(0);
+    Instant::now() + Duration::from_millis(timeout as u64)
+}
+
+/// `port:write(data)` queues `data`, blocking while the transmit buffer is
+/// full.
+unsafe extern "C-unwind" fn port_write(state: *mut c_void) -> c_int {
+    unsafe {
+        let port = check_userdata::<LuaUart>(state, 1);
+        let mut data = check_bytes(state, 2);
+        while !data.is_empty() {
+            match port.tx.blocking_write(data) {
+                Ok(n) => data = &data[n..],
+                Err(_) => return luaL_error(state, c"uart: write failed".as_ptr()),
+            }
+        }
+    }
+    0
+}
+
+/// `port:read(n [, timeout_ms])` returns up to `n` bytes, waiting up to
+/// `timeout_ms` for all of them to arrive.
+unsafe extern "C-unwind" fn port_read(state: *mut c_void) -> c_int {
+    unsafe {
+        let port = check_userdata::<LuaUart>(state, 1);
+        let len = luaL_checkinteger(state, 2);
+        if !(1..=MAX_READ).contains(&len) {
+            return luaL_error(
+                state,
+                c"uart: read must be 1..%d bytes".as_ptr(),
+                MAX_READ as c_int,
+            );
+        }
+        let len = len as usize;
+        let deadline = check_deadline(state, 3);
+        let want = len.saturating_sub(port.pending.len());
+        await_future(state, receive(want, deadline), move |state, received| {
+            // kept in the port until pushed, so an error frees them
+            let port = check_userdata::<LuaUart>(state, 1);
+            port.pending.extend_from_slice(&received);
+            drop(received);
+            check_stack(state, 1);
+            let len = len.min(port.pending.len());
+            lua_pushlstring(state, port.pending.as_ptr(), len);
+            port.pending.drain(..len);
+            1
+        })
+    }
+}
+
+/// `port:readline([timeout_ms])` returns the next line without its line
+/// ending, or `nil` if no whole line arrived in time.
+unsafe extern "C-unwind" fn port_readline(state: *mut c_void) -> c_int {
+    unsafe {
+        let port = check_userdata::<LuaUart>(state, 1);
+        let deadline = check_deadline(state, 2);
+        let room = match port.line_len() {
+            Some(_) => 0,
+            None => MAX_LINE - port.pending.len(),
+        };
+        await_future(state, receive_line(room, deadline), |state, received| {
+            let port = check_userdata::<LuaUart>(state, 1);
+            port.pending.extend_from_slice(&received);
+            drop(received);
+            check_stack(state, 1);
+            match port.line_len() {
+                Some(len) => {
+                    let line = &port.pending[..len];
+                    let line = line.strip_suffix(b"\n").unwrap_or(line);
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    lua_pushlstring(state, line.as_ptr(), line.len());
+                    port.pending.drain(..len);
+                }
+                None => lua_pushnil(state),
+            }
+            1
+        })
+    }
+}
+
+/// `port:available()` returns how many received bytes are waiting.
+unsafe extern "C-unwind" fn port_available(state: *mut c_void) -> c_int {
+    unsafe {
+        let port = check_userdata::<LuaUart>(state, 1);
+        lua_pushinteger(state, (port.pending.len() + RX.len()) as c_long);
+    }
+    1
+}
+
+/// `port:close()` stops the port and releases its pins.
+unsafe extern "C-unwind" fn port_close(state: *mut c_void) -> c_int {
+    unsafe { close_userdata::<LuaUart>(state, 1) };
+    0
+}
+
+pub unsafe extern "C-unwind" fn luaopen_uart(state: *mut c_void) -> c_int {
+    unsafe { new_lib(state, &[(c"open", uart_open)]) };
+    1
+}
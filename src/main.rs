@@ -7,28 +7,101 @@
 
 use console_ldd::{console_init, console_write};
 use defmt::*;
-use embassy_executor::Spawner;
-use embassy_rp::bind_interrupts;
+use defmt_rtt as _;
+#[cfg(feature = "core1")]
+use embassy_executor::Executor;
+use embassy_executor::{InterruptExecutor, Spawner};
+use embassy_rp::adc::{self, Adc};
+use embassy_rp::flash::{Blocking, Flash};
 use embassy_rp::gpio;
-use embassy_rp::peripherals::UART0;
+use embassy_rp::interrupt::{InterruptExt, Priority};
+#[cfg(feature = "core1")]
+use embassy_rp::multicore::{Stack, spawn_core1};
+use embassy_rp::peripherals::{PIO0, PIO1, UART0};
+use embassy_rp::pio::{self, Pio};
 use embassy_rp::uart::{Config, InterruptHandler, Uart};
-use embassy_time::Timer;
+use embassy_rp::watchdog::Watchdog;
+use embassy_rp::{bind_interrupts, interrupt};
+use embassy_time::{Duration, Timer};
 use gpio::{Level, Output};
-use {defmt_rtt as _, panic_probe as _};
+#[cfg(feature = "core1")]
+use static_cell::{ConstStaticCell, StaticCell};
 
 mod alloc;
 mod console_ldd;
+mod crash;
 mod lua;
+mod lua_adc;
+mod lua_async;
+mod lua_event;
+mod lua_gpio;
+mod lua_i2c;
+mod lua_limit;
+mod lua_msg;
+mod lua_neopixel;
+mod lua_os;
+mod lua_pio;
+mod lua_pwm;
+mod lua_spi;
+mod lua_state;
+mod lua_sys;
+mod lua_task;
+mod lua_time;
+mod lua_uart;
+mod pins;
 mod syscalls;
 
 bind_interrupts!(struct Irqs {
     UART0_IRQ => InterruptHandler<UART0>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => pio::InterruptHandler<PIO1>;
 });
 
+// The Pico's 2 MB flash, as in memory.x.
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+// How long the board may go without feeding the watchdog before it resets.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Runs driver tasks that must make progress while a script blocks the
+// thread-mode executor.
+static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_1() {
+    unsafe { EXECUTOR_HIGH.on_interrupt() }
+}
+
+// With the `core1` feature, Lua runs on the second core so that garbage
+// collection and long scripts do not hold up the tasks on core 0. The C
+// library and the interpreter recurse deeply, hence the large stack.
+#[cfg(feature = "core1")]
+static CORE1_STACK: ConstStaticCell<Stack<{ 32 * 1024 }>> = ConstStaticCell::new(Stack::new());
+#[cfg(feature = "core1")]
+static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
+
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
+    crash::init();
     let p = embassy_rp::init(Default::default());
-    let mut led = Output::new(p.PIN_25, Level::Low);
+
+    interrupt::SWI_IRQ_1.set_priority(Priority::P2);
+    let high_spawner = EXECUTOR_HIGH.start(interrupt::SWI_IRQ_1);
+    lua_gpio::init(high_spawner);
+    lua_uart::init(high_spawner);
+    let mut flash_id = [0; 8];
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(p.FLASH);
+    flash.blocking_unique_id(&mut flash_id).unwrap();
+    lua_sys::init(
+        high_spawner,
+        Watchdog::new(p.WATCHDOG),
+        WATCHDOG_TIMEOUT,
+        flash_id,
+    );
+    lua_os::set_env("BOARD", "pico");
+
+    let led = Output::new(p.PIN_25, Level::Low);
 
     let uart = Uart::new(
         p.UART0,
@@ -40,6 +113,43 @@ async fn main(_spawner: Spawner) {
         Config::default(),
     );
     let (tx, rx) = uart.split();
+
+    lua_adc::init(Adc::new(p.ADC, Irqs, adc::Config::default()), p.DMA_CH2);
+    lua_pio::init(Pio::new(p.PIO0, Irqs), Pio::new(p.PIO1, Irqs));
+    lua_neopixel::init(p.DMA_CH7);
+
+    // Everything but the console UART (PIN_0/PIN_1) and the LED (PIN_25) is
+    // up for grabs by scripts.
+    pins::init([
+        p.PIN_2.into(),
+        p.PIN_3.into(),
+        p.PIN_4.into(),
+        p.PIN_5.into(),
+        p.PIN_6.into(),
+        p.PIN_7.into(),
+        p.PIN_8.into(),
+        p.PIN_9.into(),
+        p.PIN_10.into(),
+        p.PIN_11.into(),
+        p.PIN_12.into(),
+        p.PIN_13.into(),
+        p.PIN_14.into(),
+        p.PIN_15.into(),
+        p.PIN_16.into(),
+        p.PIN_17.into(),
+        p.PIN_18.into(),
+        p.PIN_19.into(),
+        p.PIN_20.into(),
+        p.PIN_21.into(),
+        p.PIN_22.into(),
+        p.PIN_23.into(),
+        p.PIN_24.into(),
+        p.PIN_26.into(),
+        p.PIN_27.into(),
+        p.PIN_28.into(),
+        p.PIN_29.into(),
+    ]);
+
     console_init(tx, rx).await;
     console_write(concat!(
         "Embassy executor version: ",
@@ -47,9 +157,46 @@ async fn main(_spawner: Spawner) {
         "\r\n"
     ))
     .await;
+    info!("reset reason: {}", lua_sys::reset_reason());
+    console_write("Reset reason: ").await;
+    console_write(lua_sys::reset_reason()).await;
+    console_write("\r\n").await;
+    if let Some(report) = crash::last_text() {
+        console_write(&report).await;
+    }
+
+    spawner.spawn(blink(led)).unwrap();
 
-    lua::test_lua();
+    // Lua on core 1 drives the peripherals itself: every driver sits in a
+    // critical-section mutex, which the RP2040 makes safe across cores with a
+    // hardware spinlock. Each core has its own NVIC, and a driver enables its
+    // interrupt on the core that creates it. The ADC, PIO and DMA drivers
+    // made above, the GPIO edges armed by the tasks on EXECUTOR_HIGH and the
+    // uart, which hands its interrupt to its receive task, are served on
+    // core 0; an i2c bus opened by a script is served on core 1. Wakers reach
+    // core 1's executor by SEV, but pend EXECUTOR_HIGH only from core 0, so a
+    // driver whose interrupt wakes a task there must move it to core 0.
+    // Output goes through the console relay.
+    #[cfg(feature = "core1")]
+    {
+        spawn_core1(p.CORE1, CORE1_STACK.take(), || {
+            let executor = EXECUTOR1.init(Executor::new());
+            executor.run(|spawner| spawner.spawn(run_lua()).unwrap())
+        });
+        console_ldd::relay_output().await;
+    }
+    #[cfg(not(feature = "core1"))]
+    lua::test_lua().await;
+}
+
+#[cfg(feature = "core1")]
+#[embassy_executor::task]
+async fn run_lua() {
+    lua::test_lua().await;
+}
 
+#[embassy_executor::task]
+async fn blink(mut led: Output<'static>) {
     loop {
         info!("led on!");
         led.set_high();
@@ -0,0 +1,61 @@
+//! Ownership registry for the GPIO pins that scripts may claim.
+//!
+//! At boot `main` hands over the pin tokens it does not use itself. Pins the
+//! firmware keeps (the console UART, the LED) are never in the registry, so a
+//! script cannot take them away from the Rust side.
+
+use core::cell::Cell;
+use core::ffi::CStr;
+use embassy_rp::Peri;
+use embassy_rp::gpio::{AnyPin, Pin};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Number of GPIOs in bank 0.
+pub const PIN_COUNT: u8 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PinError {
+    /// No such GPIO on the RP2040.
+    Invalid,
+    /// Kept by the firmware, or already claimed by someone else.
+    InUse,
+}
+
+impl PinError {
+    pub fn message(&self) -> &'static CStr {
+        match self {
+            PinError::Invalid => c"no such pin",
+            PinError::InUse => c"pin in use",
+        }
+    }
+}
+
+// one bit per GPIO, set while the pin is free to claim
+static AVAILABLE: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Give the registry the pins the firmware does not use.
+pub fn init<const N: usize>(pins: [Peri<'static, AnyPin>; N]) {
+    let mask = pins.iter().fold(0, |mask, pin| mask | 1 << pin.pin());
+    AVAILABLE.lock(|available| available.set(available.get() | mask));
+}
+
+/// Take ownership of GPIO `pin`.
+pub fn claim(pin: u8) -> Result<Peri<'static, AnyPin>, PinError> {
+    if pin >= PIN_COUNT {
+        return Err(PinError::Invalid);
+    }
+    AVAILABLE.lock(|available| {
+        if available.get() & (1 << pin) == 0 {
+            return Err(PinError::InUse);
+        }
+        available.set(available.get() & !(1 << pin));
+        // SAFETY: the bit was set, so nobody else holds this pin
+        Ok(unsafe { AnyPin::steal(pin) })
+    })
+}
+
+/// Hand GPIO `pin` back once the driver using it has been dropped.
+pub fn release(pin: u8) {
+    AVAILABLE.lock(|available| available.set(available.get() | 1 << pin));
+}
@@ -3,8 +3,7 @@
 //! Not all 17 are implemented here. They are only implemented as needed by the
 //! application.
 use crate::console_ldd::{console_read_blocking, console_write_blocking};
-use core::ffi::{c_char, c_int};
-use defmt::panic;
+use core::ffi::{c_char, c_int, c_long, c_void};
 use defmt::*;
 
 static mut SBRK_HEAP: [u8; 2064] = [0; 2064];
@@ -19,7 +18,7 @@ pub extern "C" fn _sbrk(incr: isize) -> *mut u8 {
     unsafe {
         let heap_len = SBRK_HEAP.len();
         if SBRK_HEAP_PTR + (incr as usize) > heap_len {
-            panic!(
+            core::panic!(
                 "_sbrk OOM: {} + {} = {} > {}",
                 SBRK_HEAP_PTR,
                 incr,
@@ -82,3 +81,27 @@ pub extern "C" fn _read(file: c_int, ptr: *mut c_char, len: c_int) -> c_int {
 
     len
 }
+
+/// Newlib's `struct timeval`.
+#[repr(C)]
+pub struct Timeval {
+    tv_sec: i64,
+    tv_usec: c_long,
+}
+
+/// Backs `time()`, and so `os.time` and `os.date`, with the clock in lua_os.
+#[unsafe(no_mangle)]
+pub extern "C" fn _gettimeofday(tv: *mut Timeval, _tz: *mut c_void) -> c_int {
+    let micros = crate::lua_os::unix_micros();
+    if let Some(tv) = unsafe { tv.as_mut() } {
+        tv.tv_sec = micros.div_euclid(1_000_000);
+        tv.tv_usec = micros.rem_euclid(1_000_000) as c_long;
+    }
+    0
+}
+
+/// `exit()` and `abort()` panic, leaving a crash report with the exit code.
+#[unsafe(no_mangle)]
+pub extern "C" fn _exit(code: c_int) -> ! {
+    core::panic!("_exit({})", code)
+}